@@ -0,0 +1,208 @@
+//! Compares the sharded [`Router`] against funneling every publish through a single hub actor, with publishers
+//! spread over several threads like sockets over workers. Both deliver to subscribers the way sockets are, by sending
+//! a message to each subscribed actor, and are timed until every delivery has been handled.
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        mpsc,
+        Arc,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use actix::{
+    Actor,
+    Addr,
+    Arbiter,
+    ArbiterHandle,
+    Context,
+    Handler,
+    Message,
+    System,
+};
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use figura_api::{
+    socket::hub::Router,
+    FxHashMap,
+};
+use uuid::Uuid;
+
+const PUBLISHERS: usize = 4;
+const OWNERS: usize = 1024;
+const SUBSCRIBERS: usize = 16;
+/// Distinct subscribed actors, spread over as many arbiters as there are publishers.
+const SINKS: usize = 1024;
+
+fn owners() -> Vec<Uuid> {
+    (0..OWNERS as u128)
+        .map(|i| Uuid::from_u128(i.wrapping_mul(0x9e3779b97f4a7c15)))
+        .collect()
+}
+
+/// Stands in for a subscribed socket, counting the messages delivered to it.
+struct Sink {
+    delivered: Arc<AtomicU64>,
+}
+
+impl Actor for Sink {
+    type Context = Context<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Deliver;
+
+impl Handler<Deliver> for Sink {
+    type Result = ();
+
+    fn handle(&mut self, _: Deliver, _: &mut Self::Context) {
+        self.delivered.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// The sinks subscribed to the owner at the given index.
+fn subscribed(i: usize) -> impl Iterator<Item = usize> {
+    (0..SUBSCRIBERS).map(move |s| (i * SUBSCRIBERS + s) % SINKS)
+}
+
+/// Starts the sinks on a background system, returning them along with a spare arbiter to run a hub on.
+fn spawn(delivered: &Arc<AtomicU64>) -> (Vec<Addr<Sink>>, ArbiterHandle) {
+    let delivered = delivered.clone();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        System::new().block_on(async move {
+            let arbiters = (0..PUBLISHERS).map(|_| Arbiter::new()).collect::<Vec<_>>();
+            let sinks = (0..SINKS)
+                .map(|i| {
+                    let delivered = delivered.clone();
+                    Sink::start_in_arbiter(&arbiters[i % PUBLISHERS].handle(), move |_| Sink { delivered })
+                })
+                .collect::<Vec<_>>();
+
+            tx.send((sinks, Arbiter::new().handle())).unwrap();
+            std::future::pending::<()>().await
+        })
+    });
+
+    rx.recv().unwrap()
+}
+
+/// Splits `iters` publishes over the publisher threads, returning how long it took for all of them to be delivered.
+fn run(
+    iters: u64,
+    owners: &Arc<Vec<Uuid>>,
+    delivered: &AtomicU64,
+    publish: impl Fn(Uuid) + Send + Sync + Clone + 'static,
+) -> Duration {
+    let base = delivered.load(Ordering::Acquire);
+    let start = Instant::now();
+    let threads = (0..PUBLISHERS)
+        .map(|p| {
+            let owners = owners.clone();
+            let publish = publish.clone();
+            thread::spawn(move || {
+                for i in (p as u64..iters).step_by(PUBLISHERS) {
+                    publish(owners[i as usize % OWNERS]);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    while delivered.load(Ordering::Acquire) - base < iters * SUBSCRIBERS as u64 {
+        thread::yield_now();
+    }
+
+    start.elapsed()
+}
+
+fn sharded(c: &mut Criterion, owners: &Arc<Vec<Uuid>>) {
+    let delivered = Arc::new(AtomicU64::new(0));
+    let (sinks, _) = spawn(&delivered);
+
+    let router = Arc::new(Router::new(
+        thread::available_parallelism().map_or(1, |cores| cores.get()) * 4,
+    ));
+    for (i, &owner) in owners.iter().enumerate() {
+        for sink in subscribed(i) {
+            router.subscribe(owner, Uuid::nil(), sinks[sink].clone());
+        }
+    }
+
+    c.bench_function("sharded router", |b| {
+        b.iter_custom(|iters| {
+            let router = router.clone();
+            run(iters, owners, &delivered, move |owner| {
+                router.publish(owner, |sink| sink.do_send(Deliver))
+            })
+        })
+    });
+}
+
+struct Hub {
+    subscribers: FxHashMap<Uuid, Vec<Addr<Sink>>>,
+}
+
+impl Actor for Hub {
+    type Context = Context<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Publish(Uuid);
+
+impl Handler<Publish> for Hub {
+    type Result = ();
+
+    fn handle(&mut self, Publish(owner): Publish, _: &mut Self::Context) {
+        if let Some(sinks) = self.subscribers.get(&owner) {
+            for sink in sinks {
+                sink.do_send(Deliver);
+            }
+        }
+    }
+}
+
+fn single_actor(c: &mut Criterion, owners: &Arc<Vec<Uuid>>) {
+    let delivered = Arc::new(AtomicU64::new(0));
+    let (sinks, arbiter) = spawn(&delivered);
+    let hub = Hub {
+        subscribers: owners
+            .iter()
+            .enumerate()
+            .map(|(i, &owner)| (owner, subscribed(i).map(|sink| sinks[sink].clone()).collect()))
+            .collect(),
+    };
+    let addr = Hub::start_in_arbiter(&arbiter, move |_| hub);
+
+    c.bench_function("single actor hub", |b| {
+        b.iter_custom(|iters| {
+            let addr = addr.clone();
+            run(iters, owners, &delivered, move |owner| addr.do_send(Publish(owner)))
+        })
+    });
+}
+
+fn publish(c: &mut Criterion) {
+    let owners = Arc::new(owners());
+    sharded(c, &owners);
+    single_actor(c, &owners);
+}
+
+criterion_group!(benches, publish);
+criterion_main!(benches);
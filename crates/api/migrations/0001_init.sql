@@ -0,0 +1,38 @@
+CREATE TABLE users (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);
+
+CREATE INDEX users_name ON users (name COLLATE NOCASE);
+
+CREATE TABLE avatars (
+    owner TEXT PRIMARY KEY NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    uploaded INTEGER NOT NULL
+);
+
+CREATE INDEX avatars_hash ON avatars (hash);
+
+CREATE TABLE bans (
+    id TEXT PRIMARY KEY NOT NULL,
+    reason TEXT NOT NULL,
+    expires INTEGER
+);
+
+CREATE TABLE badges (
+    user_id TEXT NOT NULL,
+    badge TEXT NOT NULL,
+    granted INTEGER NOT NULL,
+    PRIMARY KEY (user_id, badge)
+);
+
+CREATE TABLE settings (
+    user_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (user_id, key)
+);
@@ -0,0 +1,5 @@
+ALTER TABLE avatars ADD COLUMN name TEXT NOT NULL DEFAULT '';
+ALTER TABLE avatars ADD COLUMN authors TEXT NOT NULL DEFAULT '';
+ALTER TABLE avatars ADD COLUMN version TEXT NOT NULL DEFAULT '';
+-- JSON object of each script's name to its size in bytes.
+ALTER TABLE avatars ADD COLUMN scripts TEXT NOT NULL DEFAULT '{}';
@@ -0,0 +1,6 @@
+-- Per-user overrides of the configured limits; `NULL` keeps the configured one.
+CREATE TABLE quotas (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    max_avatar_size INTEGER,
+    max_avatars INTEGER
+);
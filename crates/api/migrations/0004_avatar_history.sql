@@ -0,0 +1,15 @@
+-- The latest uploads of each user, oldest first by `id`.
+CREATE TABLE avatar_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    owner TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    uploaded INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    authors TEXT NOT NULL,
+    version TEXT NOT NULL,
+    scripts TEXT NOT NULL
+);
+
+CREATE INDEX avatar_history_owner ON avatar_history (owner, id);
+CREATE INDEX avatar_history_hash ON avatar_history (hash);
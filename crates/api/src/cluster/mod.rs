@@ -0,0 +1,181 @@
+//! Event bus between instances running behind the same load balancer. Each instance sends the events it originates
+//! to the rest of the cluster in batches, either posted directly to every peer or relayed through a RESP server, and
+//! applies the ones it receives without forwarding them any further.
+
+pub mod peer;
+pub mod redis;
+
+use std::time::Duration;
+
+use actix_web::web;
+use once_cell::sync::OnceCell;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::sync::mpsc::{
+    self,
+    error::TrySendError,
+};
+use uuid::Uuid;
+
+use crate::{
+    metrics,
+    random_uuid,
+    service::auth,
+    socket::{
+        hub,
+        message::S2C,
+    },
+};
+
+/// Events waiting to be sent before new ones get dropped.
+const BACKLOG: usize = 4096;
+/// Events sent in a single batch.
+const BATCH: usize = 256;
+
+pub enum ClusterConfig {
+    /// Posts events directly to every other instance, so the peer list must be complete on every instance.
+    Peers(peer::PeerConfig),
+    /// Relays events through a RESP server's pub/sub, which also stores access tokens for instances joining later.
+    Redis(redis::RedisConfig),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    TokenIssued {
+        token: Uuid,
+        server_id: Uuid,
+        user_id: Uuid,
+        name: String,
+        /// Index of the authenticator that issued the token, which must be configured identically on every instance.
+        auth: usize,
+    },
+    TokenRefreshed {
+        token: Uuid,
+        user_id: Uuid,
+    },
+    TokenRevoked {
+        token: Uuid,
+    },
+    UserRevoked {
+        user_id: Uuid,
+    },
+    Ping {
+        owner: Uuid,
+        id: u32,
+        #[serde(with = "base64_bytes")]
+        data: web::Bytes,
+    },
+    AvatarChanged {
+        owner: Uuid,
+    },
+}
+
+/// Events sent together. Senders may serialize them from a slice.
+#[derive(Serialize, Deserialize)]
+pub struct Batch<E = Vec<Event>> {
+    pub node: Uuid,
+    pub time: u64,
+    /// Unique to the batch, so receivers can reject batches they've already applied.
+    pub nonce: Uuid,
+    pub events: E,
+}
+
+struct Cluster {
+    /// Verifies batches if they're posted directly between instances.
+    peers: Option<peer::Peers>,
+    senders: Vec<mpsc::Sender<Event>>,
+}
+
+static CLUSTER: OnceCell<Cluster> = OnceCell::new();
+
+/// Joins the cluster, spawning its senders and receivers on the current arbiter.
+pub async fn start(config: ClusterConfig, access_timeout: Duration) -> anyhow::Result<()> {
+    let node = random_uuid();
+    let cluster = match config {
+        ClusterConfig::Peers(config) => {
+            let (peers, senders) = peer::start(node, config)?;
+            Cluster {
+                peers: Some(peers),
+                senders,
+            }
+        }
+        ClusterConfig::Redis(config) => Cluster {
+            peers: None,
+            senders: vec![redis::start(node, config, access_timeout).await?],
+        },
+    };
+
+    if CLUSTER.set(cluster).is_err() {
+        anyhow::bail!("already joined a cluster");
+    }
+
+    log::info!("Joined cluster as node {node}.");
+    Ok(())
+}
+
+/// Sends the event to the rest of the cluster, if any. Events are dropped for destinations that fall too far behind.
+pub fn broadcast(event: Event) {
+    let Some(cluster) = CLUSTER.get() else { return };
+    for sender in &cluster.senders {
+        if let Err(TrySendError::Full(..)) = sender.try_send(event.clone()) {
+            metrics::CLUSTER_EVENTS_DROPPED.add(1);
+        }
+    }
+}
+
+impl Event {
+    /// Whether the event grants or revokes access, so it must reach every instance rather than only be worth
+    /// delivering promptly.
+    #[inline]
+    pub fn is_auth(&self) -> bool {
+        !matches!(self, Event::Ping { .. } | Event::AvatarChanged { .. })
+    }
+}
+
+/// Applies an event received from another instance.
+pub fn apply(event: Event) {
+    match event {
+        Event::TokenIssued {
+            token,
+            server_id,
+            user_id,
+            name,
+            auth,
+        } => auth::import_access_token(token, server_id, user_id, name, auth, Duration::ZERO),
+        Event::TokenRefreshed { token, .. } => auth::touch_access_token(token),
+        Event::TokenRevoked { token } => {
+            auth::revoke_access_token_locally(token);
+        }
+        Event::UserRevoked { user_id } => {
+            auth::revoke_user_locally(user_id);
+        }
+        Event::Ping { owner, id, data } => hub::publish(owner, S2C::Ping(owner.as_u128(), id, data)),
+        Event::AvatarChanged { owner } => hub::publish(owner, S2C::Event(owner.as_u128())),
+    }
+}
+
+mod base64_bytes {
+    use actix_web::web;
+    use base64::{
+        engine::general_purpose::STANDARD,
+        Engine,
+    };
+    use serde::{
+        de::Error,
+        Deserialize,
+        Deserializer,
+        Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(data: &web::Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<web::Bytes, D::Error> {
+        let data = String::deserialize(deserializer)?;
+        STANDARD.decode(data).map(web::Bytes::from).map_err(D::Error::custom)
+    }
+}
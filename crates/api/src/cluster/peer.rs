@@ -0,0 +1,417 @@
+//! Transport posting signed batches directly to every peer over HTTPS.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    mem,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{
+    http::header::CONTENT_TYPE,
+    rt::{
+        spawn,
+        time::sleep,
+    },
+};
+use aws_lc_rs::hmac;
+use base64::{
+    engine::general_purpose::STANDARD,
+    Engine,
+};
+use parking_lot::Mutex;
+use rustls::ClientConfig;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    cluster::{
+        Batch,
+        Event,
+        BACKLOG,
+        BATCH,
+        CLUSTER,
+    },
+    metrics,
+    native_roots,
+    random_uuid,
+    unix_now,
+    FxHashSet,
+};
+
+/// Header carrying the base64-encoded HMAC-SHA256 of a batch, keyed with the cluster secret.
+pub const SIGNATURE: &str = "cluster-signature";
+
+/// How old a batch may be before it's rejected as a replay.
+const MAX_AGE: u64 = 60;
+/// Largest body of a batch in bytes, which peers accept from each other.
+pub const MAX_BATCH_SIZE: usize = 1 << 20;
+/// Room in a batch's body for everything but its events.
+const ENVELOPE_SIZE: usize = 256;
+/// How many more times auth events are posted to a peer that didn't take them, waiting twice as long each time.
+const RETRIES: u32 = 5;
+
+pub struct PeerConfig {
+    /// Base URLs of every other instance, e.g. `https://10.0.0.2:443`.
+    pub peers: Vec<String>,
+    /// Secret shared by every instance, used to sign batches.
+    pub secret: Vec<u8>,
+    /// PEM file of additional certificate authorities to trust when connecting to peers.
+    pub peer_ca: Option<PathBuf>,
+}
+
+/// Nonces of the batches received within [`MAX_AGE`], in the order they arrived.
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<(u64, Uuid)>,
+    nonces: FxHashSet<Uuid>,
+}
+
+/// This node's end of the transport, verifying the batches posted to it.
+pub struct Peers {
+    node: Uuid,
+    key: hmac::Key,
+    seen: Mutex<Seen>,
+}
+
+impl Peers {
+    /// Checks the batch was signed with the cluster secret by another node recently and hasn't been received before,
+    /// returning it if so.
+    pub fn verify(&self, signature: &str, body: &[u8]) -> Option<Batch> {
+        let signature = STANDARD.decode(signature).ok()?;
+        hmac::verify(&self.key, body, &signature).ok()?;
+
+        let batch = serde_json::from_slice::<Batch>(body).ok()?;
+        let now = unix_now();
+        if batch.node == self.node || now.abs_diff(batch.time) > MAX_AGE {
+            return None
+        }
+
+        // Batches old enough to be rejected by age alone needn't be remembered. Arrival order is only roughly the
+        // order of their timestamps, which at worst keeps a few around for longer.
+        let mut seen = self.seen.lock();
+        while let Some(&(time, nonce)) = seen.order.front() {
+            if now.saturating_sub(MAX_AGE) <= time {
+                break
+            }
+
+            seen.order.pop_front();
+            seen.nonces.remove(&nonce);
+        }
+
+        if !seen.nonces.insert(batch.nonce) {
+            return None
+        }
+
+        seen.order.push_back((batch.time, batch.nonce));
+        Some(batch)
+    }
+}
+
+pub(super) fn start(node: Uuid, config: PeerConfig) -> anyhow::Result<(Peers, Vec<mpsc::Sender<Event>>)> {
+    let PeerConfig { peers, secret, peer_ca } = config;
+    if secret.is_empty() {
+        anyhow::bail!("the cluster secret must not be empty");
+    }
+
+    let mut roots = native_roots();
+    if let Some(path) = peer_ca {
+        for cert in rustls_pemfile::certs(&mut &*fs::read(&path)?) {
+            roots.add(cert?)?;
+        }
+    }
+
+    let client = Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth());
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &secret);
+
+    let senders = peers
+        .into_iter()
+        .map(|peer| {
+            let (tx, rx) = mpsc::channel(BACKLOG);
+            spawn(send(
+                node,
+                key.clone(),
+                client.clone(),
+                format!("{}/api/cluster/events", peer.trim_end_matches('/')),
+                rx,
+            ));
+            tx
+        })
+        .collect();
+
+    Ok((
+        Peers {
+            node,
+            key,
+            seen: Mutex::default(),
+        },
+        senders,
+    ))
+}
+
+/// Splits the events into batches whose bodies stay within [`MAX_BATCH_SIZE`], dropping any too large to send at all.
+fn split(events: Vec<Event>) -> Vec<Vec<Event>> {
+    let mut batches = Vec::new();
+    let (mut batch, mut size) = (Vec::new(), ENVELOPE_SIZE);
+    for event in events {
+        // Followed by a comma in the batch.
+        let len = serde_json::to_vec(&event).expect("couldn't serialize event").len() + 1;
+        if ENVELOPE_SIZE + len > MAX_BATCH_SIZE {
+            metrics::CLUSTER_EVENTS_DROPPED.add(1);
+            continue
+        }
+
+        if size + len > MAX_BATCH_SIZE {
+            batches.push(mem::take(&mut batch));
+            size = ENVELOPE_SIZE;
+        }
+
+        batch.push(event);
+        size += len;
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+async fn post(client: &awc::Client, node: Uuid, key: &hmac::Key, url: &str, events: &[Event]) -> Result<(), String> {
+    let batch = Batch {
+        node,
+        time: unix_now(),
+        nonce: random_uuid(),
+        events,
+    };
+
+    let body = serde_json::to_vec(&batch).expect("couldn't serialize batch");
+    let signature = STANDARD.encode(hmac::sign(key, &body));
+
+    match client
+        .post(url)
+        .insert_header((CONTENT_TYPE, "application/json"))
+        .insert_header((SIGNATURE, signature))
+        .send_body(body)
+        .await
+    {
+        Ok(res) if res.status().is_success() => Ok(()),
+        Ok(res) => Err(res.status().to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn send(node: Uuid, key: hmac::Key, config: Arc<ClientConfig>, url: String, mut rx: mpsc::Receiver<Event>) {
+    let client = awc::Client::builder()
+        .connector(awc::Connector::new().rustls_0_23(config))
+        .timeout(Duration::from_secs(5))
+        .finish();
+
+    let mut events = Vec::with_capacity(BATCH);
+    while rx.recv_many(&mut events, BATCH).await > 0 {
+        for mut batch in split(mem::take(&mut events)) {
+            let mut retries = 0;
+            while let Err(e) = post(&client, node, &key, &url, &batch).await {
+                log::warn!("Peer `{url}` didn't take {} events: {e}", batch.len());
+
+                // Tokens left valid on a peer would outlive their revocation, while anything else is stale by now.
+                let len = batch.len();
+                batch.retain(Event::is_auth);
+                if retries == RETRIES {
+                    batch.clear();
+                }
+
+                metrics::CLUSTER_EVENTS_DROPPED.add((len - batch.len()) as u64);
+                if batch.is_empty() {
+                    break
+                }
+
+                sleep(Duration::from_secs(1 << retries)).await;
+                retries += 1;
+            }
+        }
+    }
+}
+
+/// Verifies a batch posted to this node, if it joined the cluster through peers. See [`Peers::verify`].
+pub fn verify(signature: &str, body: &[u8]) -> Option<Batch> {
+    CLUSTER.get()?.peers.as_ref()?.verify(signature, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{
+            SocketAddr,
+            TcpListener,
+        },
+        sync::atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+    };
+
+    use actix_web::{
+        rt::time::timeout,
+        web,
+        App,
+        HttpRequest,
+        HttpResponse,
+        HttpServer,
+    };
+
+    use super::*;
+
+    type Posted = (String, web::Bytes);
+
+    async fn receive(
+        req: HttpRequest,
+        body: web::Bytes,
+        posted: web::Data<mpsc::UnboundedSender<Posted>>,
+        refusals: web::Data<AtomicUsize>,
+    ) -> HttpResponse {
+        let signature = req
+            .headers()
+            .get(SIGNATURE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let _ = posted.send((signature.to_string(), body));
+
+        match refusals.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)) {
+            Ok(..) => HttpResponse::ServiceUnavailable().finish(),
+            Err(..) => HttpResponse::NoContent().finish(),
+        }
+    }
+
+    /// Starts a node posting to the peer, returning its transport, its sender and the batches posted to it, the first
+    /// `refusals` of which are refused.
+    fn node(
+        listener: TcpListener,
+        peer: SocketAddr,
+        refusals: usize,
+    ) -> (Peers, mpsc::Sender<Event>, mpsc::UnboundedReceiver<Posted>) {
+        let (peers, mut senders) = start(random_uuid(), PeerConfig {
+            peers: vec![format!("http://{peer}/")],
+            secret: b"secret".to_vec(),
+            peer_ca: None,
+        })
+        .unwrap();
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let refusals = web::Data::new(AtomicUsize::new(refusals));
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(tx.clone()))
+                .app_data(refusals.clone())
+                .app_data(web::PayloadConfig::new(MAX_BATCH_SIZE))
+                .route("/api/cluster/events", web::post().to(receive))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        spawn(server);
+
+        (peers, senders.pop().unwrap(), rx)
+    }
+
+    async fn next(posted: &mut mpsc::UnboundedReceiver<Posted>) -> Posted {
+        timeout(Duration::from_secs(5), posted.recv())
+            .await
+            .expect("nothing was posted")
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn propagates_between_nodes() {
+        let (a, b) = (
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+        );
+        let (addr_a, addr_b) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let (peers_a, to_b, mut at_a) = node(a, addr_b, 0);
+        let (peers_b, to_a, mut at_b) = node(b, addr_a, 0);
+
+        let token = Uuid::from_u128(1);
+        to_b.send(Event::TokenIssued {
+            token,
+            server_id: Uuid::nil(),
+            user_id: Uuid::from_u128(10),
+            name: "issued".to_string(),
+            auth: 0,
+        })
+        .await
+        .unwrap();
+
+        let (signature, body) = next(&mut at_b).await;
+        let batch = peers_b.verify(&signature, &body).expect("issue was rejected");
+        assert!(matches!(batch.events[..], [Event::TokenIssued { token: issued, .. }] if issued == token));
+        assert!(peers_b.verify(&signature, &body).is_none(), "replayed issue was accepted");
+        assert!(peers_a.verify(&signature, &body).is_none(), "node accepted its own batch");
+
+        to_a.send(Event::TokenRevoked { token }).await.unwrap();
+
+        let (signature, body) = next(&mut at_a).await;
+        let batch = peers_a.verify(&signature, &body).expect("revocation was rejected");
+        assert!(matches!(batch.events[..], [Event::TokenRevoked { token: revoked }] if revoked == token));
+        assert!(
+            peers_a.verify(&signature, &body).is_none(),
+            "replayed revocation was accepted"
+        );
+
+        let mut batch = serde_json::from_slice::<Batch>(&body).unwrap();
+        batch.nonce = random_uuid();
+        let body = serde_json::to_vec(&batch).unwrap();
+        let forged = STANDARD.encode(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"other"), &body));
+        assert!(
+            peers_a.verify(&forged, &body).is_none(),
+            "batch signed with another secret was accepted"
+        );
+    }
+    #[actix_web::test]
+    async fn splits_batches_and_retries_auth_events() {
+        let (a, b) = (
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+        );
+        let addr_b = b.local_addr().unwrap();
+        let (.., to_b, _) = node(a, addr_b, 0);
+        let (peers_b, .., mut at_b) = node(b, addr_b, 1);
+
+        // Relayed pings take up to a socket message each, so a full batch of them is well over the limit.
+        let token = random_uuid();
+        to_b.send(Event::TokenRevoked { token }).await.unwrap();
+        for id in 0..40 {
+            to_b.send(Event::Ping {
+                owner: Uuid::nil(),
+                id,
+                data: web::Bytes::from(vec![0; 64 * 1024]),
+            })
+            .await
+            .unwrap();
+        }
+
+        let mut batches = Vec::new();
+        let mut pings = 0;
+        while pings < 40 {
+            let (signature, body) = next(&mut at_b).await;
+            assert!(
+                body.len() <= MAX_BATCH_SIZE,
+                "batch of {} bytes is over the limit",
+                body.len()
+            );
+
+            let batch = peers_b.verify(&signature, &body).expect("batch was rejected");
+            pings += batch.events.iter().filter(|event| !event.is_auth()).count();
+            batches.push(batch.events);
+        }
+
+        // Only the revocation is posted again after the first batch was refused, and its pings are gone.
+        assert!(matches!(batches[0][0], Event::TokenRevoked { token: revoked } if revoked == token));
+        assert!(matches!(batches[1][..], [Event::TokenRevoked { token: revoked }] if revoked == token));
+        assert!(batches[0].len() > 1);
+        assert!(timeout(Duration::from_millis(500), at_b.recv()).await.is_err());
+    }
+}
@@ -0,0 +1,601 @@
+//! Transport relaying batches through a RESP server's pub/sub channel. Access tokens are also stored there, expiring
+//! along with them, so instances joining the cluster later can pick up the ones issued before.
+
+use std::{
+    mem,
+    time::Duration,
+};
+
+use actix_web::rt::{
+    spawn,
+    time::sleep,
+};
+use futures_util::StreamExt;
+use redis::{
+    aio::MultiplexedConnection,
+    Client,
+    Pipeline,
+    RedisResult,
+};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    cluster::{
+        self,
+        Batch,
+        Event,
+        BACKLOG,
+        BATCH,
+    },
+    encode_uuid,
+    random_uuid,
+    service::auth,
+    unix_now,
+    FxHashSet,
+};
+
+const CHANNEL: &str = "figura:events";
+const TOKEN_PREFIX: &str = "figura:token:";
+const USER_PREFIX: &str = "figura:user:";
+
+/// How long to wait before reconnecting to a server that went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub struct RedisConfig {
+    /// Connection URL, e.g. `redis://:password@127.0.0.1:6379/0`.
+    pub url: String,
+}
+
+#[inline]
+fn token_key(token: Uuid) -> String {
+    format!("{TOKEN_PREFIX}{}", encode_uuid(token))
+}
+
+#[inline]
+fn user_key(user_id: Uuid) -> String {
+    format!("{USER_PREFIX}{}", encode_uuid(user_id))
+}
+
+pub(super) async fn start(node: Uuid, config: RedisConfig, access_timeout: Duration) -> anyhow::Result<mpsc::Sender<Event>> {
+    let client = Client::open(config.url)?;
+    let mut conn = client.get_multiplexed_async_connection().await?;
+
+    let loaded = load(&mut conn, access_timeout).await?;
+    log::info!("Loaded {} access tokens from the cluster.", loaded.len());
+
+    let (tx, rx) = mpsc::channel(BACKLOG);
+    spawn(send(node, client.clone(), conn, access_timeout, rx));
+    spawn(receive(node, client, access_timeout));
+
+    Ok(tx)
+}
+
+/// Imports every access token stored on the server along with how long it has left, returning the imported tokens.
+async fn load(conn: &mut MultiplexedConnection, access_timeout: Duration) -> RedisResult<FxHashSet<Uuid>> {
+    let mut loaded = FxHashSet::default();
+    let mut cursor = 0u64;
+    loop {
+        let (next, keys) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{TOKEN_PREFIX}*"))
+            .arg("COUNT")
+            .arg(BATCH)
+            .query_async::<(u64, Vec<String>)>(conn)
+            .await?;
+
+        if !keys.is_empty() {
+            let mut pipe = redis::pipe();
+            for key in &keys {
+                pipe.cmd("GET").arg(key).cmd("PTTL").arg(key);
+            }
+
+            let values = pipe.query_async::<Vec<(Option<Vec<u8>>, i64)>>(conn).await?;
+            for (value, ttl) in values {
+                // Keys that expired in the meantime have no value.
+                let Some(value) = value else { continue };
+                match serde_json::from_slice::<Event>(&value) {
+                    Ok(Event::TokenIssued {
+                        token,
+                        server_id,
+                        user_id,
+                        name,
+                        auth,
+                    }) => {
+                        // A negative TTL means the key doesn't expire, which tokens are only stored without by hand.
+                        let age = u64::try_from(ttl).map_or(Duration::ZERO, |ttl| {
+                            access_timeout.saturating_sub(Duration::from_millis(ttl))
+                        });
+                        auth::import_access_token(token, server_id, user_id, name, auth, age);
+                        loaded.insert(token);
+                    }
+                    Ok(..) => {}
+                    Err(e) => log::warn!("Skipping malformed stored access token: {e}"),
+                }
+            }
+        }
+
+        cursor = next;
+        if cursor == 0 {
+            break Ok(loaded)
+        }
+    }
+}
+
+/// Mirrors the event's effect on access tokens to the server.
+async fn store(
+    conn: &mut MultiplexedConnection,
+    pipe: &mut Pipeline,
+    event: &Event,
+    access_timeout: Duration,
+) -> RedisResult<()> {
+    let ttl = access_timeout.as_secs().max(1);
+    match *event {
+        Event::TokenIssued { token, user_id, .. } => {
+            let value = serde_json::to_vec(event).expect("couldn't serialize event");
+            pipe.cmd("SET").arg(token_key(token)).arg(value).arg("EX").arg(ttl).ignore();
+            pipe.cmd("SADD").arg(user_key(user_id)).arg(encode_uuid(token)).ignore();
+            pipe.cmd("EXPIRE").arg(user_key(user_id)).arg(ttl).ignore();
+        }
+        Event::TokenRefreshed { token, user_id } => {
+            pipe.cmd("EXPIRE").arg(token_key(token)).arg(ttl).ignore();
+            pipe.cmd("EXPIRE").arg(user_key(user_id)).arg(ttl).ignore();
+        }
+        Event::TokenRevoked { token } => {
+            pipe.cmd("DEL").arg(token_key(token)).ignore();
+        }
+        Event::UserRevoked { user_id } => {
+            let tokens = redis::cmd("SMEMBERS")
+                .arg(user_key(user_id))
+                .query_async::<Vec<String>>(conn)
+                .await?;
+
+            for token in tokens {
+                pipe.cmd("DEL").arg(format!("{TOKEN_PREFIX}{token}")).ignore();
+            }
+            pipe.cmd("DEL").arg(user_key(user_id)).ignore();
+        }
+        Event::Ping { .. } | Event::AvatarChanged { .. } => {}
+    }
+
+    Ok(())
+}
+
+async fn send(
+    node: Uuid,
+    client: Client,
+    conn: MultiplexedConnection,
+    access_timeout: Duration,
+    mut rx: mpsc::Receiver<Event>,
+) {
+    let mut conn = Some(conn);
+    let mut events = Vec::with_capacity(BATCH);
+    while rx.recv_many(&mut events, BATCH).await > 0 {
+        let batch = Batch {
+            node,
+            time: unix_now(),
+            nonce: random_uuid(),
+            events: mem::take(&mut events),
+        };
+
+        let result = async {
+            let conn = match conn {
+                Some(ref mut conn) => conn,
+                None => conn.insert(client.get_multiplexed_async_connection().await?),
+            };
+
+            let mut pipe = redis::pipe();
+            for event in &batch.events {
+                store(conn, &mut pipe, event, access_timeout).await?;
+            }
+
+            let body = serde_json::to_vec(&batch).expect("couldn't serialize batch");
+            pipe.cmd("PUBLISH").arg(CHANNEL).arg(body).ignore();
+            pipe.query_async::<()>(conn).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            log::warn!("Couldn't relay {} events through the cluster server: {e}", batch.events.len());
+            conn = None;
+        }
+    }
+}
+
+/// Imports the tokens stored on the server again, dropping the imported ones that were removed from it while this
+/// instance wasn't subscribed to hear about it.
+async fn reload(client: &Client, access_timeout: Duration) -> RedisResult<()> {
+    let before = auth::imported_access_tokens();
+
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let loaded = load(&mut conn, access_timeout).await?;
+
+    let dropped = before
+        .into_iter()
+        .filter(|token| !loaded.contains(token))
+        .filter(|&token| auth::revoke_access_token_locally(token))
+        .count();
+    if dropped > 0 {
+        log::info!("Dropped {dropped} access tokens removed from the cluster while unsubscribed.");
+    }
+
+    Ok(())
+}
+
+async fn receive(node: Uuid, client: Client, access_timeout: Duration) {
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(CHANNEL).await {
+                Ok(()) => {
+                    // Events published before subscribing, even while loading at startup, would be missed otherwise.
+                    if let Err(e) = reload(&client, access_timeout).await {
+                        log::warn!("Couldn't reload access tokens from the cluster server: {e}");
+                    }
+
+                    let mut messages = pubsub.into_on_message();
+                    while let Some(msg) = messages.next().await {
+                        match serde_json::from_slice::<Batch>(msg.get_payload_bytes()) {
+                            Ok(batch) if batch.node == node => {}
+                            Ok(batch) => batch.events.into_iter().for_each(cluster::apply),
+                            Err(e) => log::warn!("Skipping malformed cluster batch: {e}"),
+                        }
+                    }
+
+                    log::warn!("Lost subscription to the cluster server.");
+                }
+                Err(e) => log::warn!("Couldn't subscribe to the cluster server: {e}"),
+            },
+            Err(e) => log::warn!("Couldn't connect to the cluster server: {e}"),
+        }
+
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroUsize,
+        sync::Arc,
+        time::Instant,
+    };
+
+    use actix_web::rt::{
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+        spawn,
+        time::sleep,
+    };
+    use parking_lot::Mutex;
+    use redis::AsyncCommands;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{
+        cluster::ClusterConfig,
+        service::{
+            auth::AuthService,
+            ban::BanService,
+            database::DatabaseService,
+        },
+        FxHashMap,
+    };
+
+    /// Just enough of a RESP server to stand in for Redis: strings, sets, key scans and pub/sub, tracking expiry
+    /// without enforcing it.
+    #[derive(Default)]
+    struct StandIn {
+        strings: FxHashMap<Vec<u8>, Vec<u8>>,
+        sets: FxHashMap<Vec<u8>, Vec<Vec<u8>>>,
+        expiry: FxHashMap<Vec<u8>, Instant>,
+        subscribers: Vec<(Vec<u8>, mpsc::UnboundedSender<Vec<u8>>)>,
+    }
+
+    fn bulk(out: &mut Vec<u8>, data: &[u8]) {
+        out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+        out.extend_from_slice(data);
+        out.extend_from_slice(b"\r\n");
+    }
+
+    fn array<'a>(out: &mut Vec<u8>, items: impl ExactSizeIterator<Item = &'a [u8]>) {
+        out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+        for item in items {
+            bulk(out, item);
+        }
+    }
+
+    /// Parses a complete command off the front of the buffer, if there is one.
+    fn parse(buf: &mut Vec<u8>) -> Option<Vec<Vec<u8>>> {
+        fn line(buf: &[u8], at: usize) -> Option<(usize, usize)> {
+            let end = at + buf[at..].windows(2).position(|w| w == b"\r\n")?;
+            Some((std::str::from_utf8(&buf[at + 1..end]).ok()?.parse().ok()?, end + 2))
+        }
+
+        let (len, mut at) = line(buf, 0)?;
+        let mut args = Vec::with_capacity(len);
+        for _ in 0..len {
+            let (size, start) = line(buf, at)?;
+            if buf.len() < start + size + 2 {
+                return None
+            }
+
+            args.push(buf[start..start + size].to_vec());
+            at = start + size + 2;
+        }
+
+        buf.drain(..at);
+        Some(args)
+    }
+
+    impl StandIn {
+        fn expire(&mut self, key: &[u8], secs: &[u8]) {
+            let secs = std::str::from_utf8(secs).unwrap().parse().unwrap();
+            self.expiry.insert(key.to_vec(), Instant::now() + Duration::from_secs(secs));
+        }
+
+        fn execute(&mut self, args: Vec<Vec<u8>>, tx: &mpsc::UnboundedSender<Vec<u8>>) -> Vec<u8> {
+            let mut out = Vec::new();
+            match &*args[0].to_ascii_uppercase() {
+                b"SET" => {
+                    self.strings.insert(args[1].clone(), args[2].clone());
+                    self.expiry.remove(&args[1]);
+                    if args.get(3).is_some_and(|arg| arg.eq_ignore_ascii_case(b"EX")) {
+                        self.expire(&args[1], &args[4]);
+                    }
+                    out.extend_from_slice(b"+OK\r\n");
+                }
+                b"EXPIRE" => {
+                    let exists = self.strings.contains_key(&args[1]) || self.sets.contains_key(&args[1]);
+                    if exists {
+                        self.expire(&args[1], &args[2]);
+                    }
+                    out.extend_from_slice(format!(":{}\r\n", exists as u8).as_bytes());
+                }
+                b"PTTL" => {
+                    let ttl = match self.expiry.get(&args[1]) {
+                        _ if !self.strings.contains_key(&args[1]) && !self.sets.contains_key(&args[1]) => -2,
+                        Some(at) => at.saturating_duration_since(Instant::now()).as_millis() as i64,
+                        None => -1,
+                    };
+                    out.extend_from_slice(format!(":{ttl}\r\n").as_bytes());
+                }
+                b"GET" => match self.strings.get(&args[1]) {
+                    Some(value) => bulk(&mut out, value),
+                    None => out.extend_from_slice(b"$-1\r\n"),
+                },
+                b"MGET" => {
+                    out.extend_from_slice(format!("*{}\r\n", args.len() - 1).as_bytes());
+                    for key in &args[1..] {
+                        match self.strings.get(key) {
+                            Some(value) => bulk(&mut out, value),
+                            None => out.extend_from_slice(b"$-1\r\n"),
+                        }
+                    }
+                }
+                b"DEL" => {
+                    let removed = args[1..]
+                        .iter()
+                        .filter(|key| {
+                            self.expiry.remove(*key);
+                            self.strings.remove(*key).is_some() | self.sets.remove(*key).is_some()
+                        })
+                        .count();
+                    out.extend_from_slice(format!(":{removed}\r\n").as_bytes());
+                }
+                b"SADD" => {
+                    self.sets.entry(args[1].clone()).or_default().extend_from_slice(&args[2..]);
+                    out.extend_from_slice(format!(":{}\r\n", args.len() - 2).as_bytes());
+                }
+                b"SMEMBERS" => {
+                    let members = self.sets.get(&args[1]).cloned().unwrap_or_default();
+                    array(&mut out, members.iter().map(Vec::as_slice));
+                }
+                b"SCAN" => {
+                    let prefix = args[3].strip_suffix(b"*").unwrap_or(&args[3]);
+                    let keys = self
+                        .strings
+                        .keys()
+                        .filter(|key| key.starts_with(prefix))
+                        .cloned()
+                        .collect::<Vec<_>>();
+
+                    out.extend_from_slice(b"*2\r\n");
+                    bulk(&mut out, b"0");
+                    array(&mut out, keys.iter().map(Vec::as_slice));
+                }
+                b"PUBLISH" => {
+                    self.subscribers.retain(|(.., subscriber)| !subscriber.is_closed());
+
+                    let mut message = Vec::new();
+                    array(&mut message, [&b"message"[..], &args[1], &args[2]].into_iter());
+
+                    let mut received = 0;
+                    for (channel, subscriber) in &self.subscribers {
+                        if *channel == args[1] && subscriber.send(message.clone()).is_ok() {
+                            received += 1;
+                        }
+                    }
+
+                    out.extend_from_slice(format!(":{received}\r\n").as_bytes());
+                }
+                b"SUBSCRIBE" => {
+                    self.subscribers.push((args[1].clone(), tx.clone()));
+                    out.extend_from_slice(b"*3\r\n");
+                    bulk(&mut out, b"subscribe");
+                    bulk(&mut out, &args[1]);
+                    out.extend_from_slice(b":1\r\n");
+                }
+                b"PING" => out.extend_from_slice(b"+PONG\r\n"),
+                // `CLIENT SETINFO` and whatever else.
+                _ => out.extend_from_slice(b"+OK\r\n"),
+            }
+
+            out
+        }
+
+        async fn serve(this: Arc<Mutex<Self>>, stream: TcpStream) {
+            let (mut read, mut write) = stream.into_split();
+            let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+            spawn(async move {
+                while let Some(out) = rx.recv().await {
+                    if write.write_all(&out).await.is_err() {
+                        break
+                    }
+                }
+            });
+
+            let mut buf = Vec::new();
+            let mut chunk = [0; 4096];
+            while let Ok(len @ 1..) = read.read(&mut chunk).await {
+                buf.extend_from_slice(&chunk[..len]);
+                while let Some(args) = parse(&mut buf) {
+                    let out = this.lock().execute(args, &tx);
+                    let _ = tx.send(out);
+                }
+            }
+        }
+
+        async fn start() -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let this = Arc::new(Mutex::new(Self::default()));
+            spawn(async move {
+                while let Ok((stream, ..)) = listener.accept().await {
+                    spawn(Self::serve(this.clone(), stream));
+                }
+            });
+
+            format!("redis://{addr}/")
+        }
+    }
+
+    #[actix_web::test]
+    async fn shares_tokens_through_stand_in() {
+        let url = StandIn::start().await;
+        let client = Client::open(url.clone()).unwrap();
+        let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+
+        // Another instance issued this token before this one joined.
+        let (early, user_id) = (Uuid::from_u128(1), Uuid::from_u128(10));
+        let stored = Event::TokenIssued {
+            token: early,
+            server_id: Uuid::nil(),
+            user_id,
+            name: "early".to_string(),
+            auth: 0,
+        };
+
+        conn.set::<_, _, ()>(token_key(early), serde_json::to_vec(&stored).unwrap())
+            .await
+            .unwrap();
+
+        cluster::start(ClusterConfig::Redis(RedisConfig { url }), Duration::from_secs(600))
+            .await
+            .unwrap();
+
+        let database = DatabaseService::open_in_memory().unwrap();
+        let auth = AuthService::new(
+            Duration::from_secs(10),
+            Duration::from_secs(600),
+            NonZeroUsize::new(8).unwrap(),
+            Arc::default(),
+            None,
+            BanService::open(database.clone(), None).await.unwrap(),
+            database,
+        );
+        assert_eq!(auth.check_access_token(early), Some(user_id));
+
+        // Another instance revokes it.
+        let revoke = serde_json::to_vec(&Batch {
+            node: Uuid::from_u128(2),
+            time: unix_now(),
+            nonce: Uuid::from_u128(4),
+            events: vec![Event::TokenRevoked { token: early }],
+        })
+        .unwrap();
+
+        let mut revoked = false;
+        for _ in 0..50 {
+            conn.publish::<_, _, ()>(CHANNEL, &revoke).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+
+            if auth.check_access_token(early).is_none() {
+                revoked = true;
+                break
+            }
+        }
+        assert!(revoked, "revocation wasn't relayed");
+
+        // This instance issues a token, which gets stored for instances joining later.
+        let late = Uuid::from_u128(3);
+        cluster::broadcast(Event::TokenIssued {
+            token: late,
+            server_id: Uuid::nil(),
+            user_id,
+            name: "late".to_string(),
+            auth: 0,
+        });
+
+        let mut stored = None;
+        for _ in 0..50 {
+            stored = conn.get::<_, Option<Vec<u8>>>(token_key(late)).await.unwrap();
+            if stored.is_some() {
+                break
+            }
+
+            sleep(Duration::from_millis(50)).await;
+        }
+
+        let stored = serde_json::from_slice::<Event>(&stored.expect("token wasn't stored")).unwrap();
+        assert!(matches!(stored, Event::TokenIssued { token, .. } if token == late));
+
+        // Tokens stored while this instance wasn't subscribed are picked up once it resubscribes, along with how much
+        // of their lifetime is left.
+        let (kept, removed) = (Uuid::from_u128(21), Uuid::from_u128(22));
+        let access_timeout = Duration::from_secs(600);
+        let mut pipe = redis::pipe();
+        for token in [kept, removed] {
+            let issued = Event::TokenIssued {
+                token,
+                server_id: Uuid::nil(),
+                user_id,
+                name: "reloaded".to_string(),
+                auth: 0,
+            };
+            store(&mut conn, &mut pipe, &issued, access_timeout).await.unwrap();
+        }
+        pipe.query_async::<()>(&mut conn).await.unwrap();
+        conn.expire::<_, ()>(token_key(kept), 60).await.unwrap();
+        conn.expire::<_, ()>(user_key(user_id), 60).await.unwrap();
+
+        reload(&client, access_timeout).await.unwrap();
+        assert_eq!(auth.check_access_token(kept), Some(user_id));
+        assert_eq!(auth.check_access_token(removed), Some(user_id));
+
+        // Refreshing the token keeps the user's index around as long as it.
+        let mut pipe = redis::pipe();
+        let refreshed = Event::TokenRefreshed { token: kept, user_id };
+        store(&mut conn, &mut pipe, &refreshed, access_timeout).await.unwrap();
+        pipe.query_async::<()>(&mut conn).await.unwrap();
+        for key in [token_key(kept), user_key(user_id)] {
+            let ttl = redis::cmd("PTTL").arg(&key).query_async::<i64>(&mut conn).await.unwrap();
+            assert!(ttl > 60_000, "{key} wasn't refreshed");
+        }
+
+        // The other token was revoked while this instance wasn't subscribed.
+        conn.del::<_, ()>(token_key(removed)).await.unwrap();
+        reload(&client, access_timeout).await.unwrap();
+        assert_eq!(auth.check_access_token(kept), Some(user_id));
+        assert_eq!(auth.check_access_token(removed), None);
+    }
+}
@@ -0,0 +1,200 @@
+use actix_web::{
+    delete,
+    get,
+    post,
+    put,
+    web,
+    HttpResponse,
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    endpoint::header::AccessToken,
+    metrics::Metrics,
+    service::{
+        auth::AuthService,
+        avatar::{
+            AvatarService,
+            QuotaOverride,
+        },
+        ban::{
+            Ban,
+            BanService,
+        },
+        database::DatabaseService,
+    },
+    socket::hub,
+    unix_now,
+};
+
+#[derive(Deserialize)]
+pub struct BanRequest {
+    pub reason: String,
+    /// How long the ban lasts in seconds, or `None` if it's permanent.
+    pub duration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct GcRequest {
+    /// Only reports what would be swept.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[inline]
+fn forbidden() -> HttpResponse {
+    HttpResponse::Forbidden().body("invalid access token or not an administrator")
+}
+
+#[get("/api/admin/bans")]
+pub async fn list_bans(
+    web::Header(token): web::Header<AccessToken>,
+    auth: web::Data<AuthService>,
+    bans: web::Data<BanService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    HttpResponse::Ok().json(bans.list())
+}
+
+#[put("/api/admin/bans/{id}")]
+pub async fn add_ban(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    web::Json(BanRequest { reason, duration }): web::Json<BanRequest>,
+    auth: web::Data<AuthService>,
+    bans: web::Data<BanService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    let id = id.into_inner();
+    match bans
+        .add(Ban {
+            id,
+            reason,
+            expires: duration.map(|duration| unix_now() + duration),
+        })
+        .await
+    {
+        Ok(()) => {
+            auth.revoke_user(id);
+            HttpResponse::Ok().body("banned")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/api/admin/bans/{id}")]
+pub async fn remove_ban(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    auth: web::Data<AuthService>,
+    bans: web::Data<BanService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    match bans.remove(id.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().body("unbanned"),
+        Ok(false) => HttpResponse::NotFound().body("not banned"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/api/admin/quotas/{id}")]
+pub async fn get_quota(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    match avatars.usage(id.into_inner()).await {
+        Ok(usage) => HttpResponse::Ok().json(usage),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("/api/admin/quotas/{id}")]
+pub async fn set_quota(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    web::Json(quota): web::Json<QuotaOverride>,
+    auth: web::Data<AuthService>,
+    database: web::Data<DatabaseService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    match database.set_quota(id.into_inner(), Some(quota)).await {
+        Ok(..) => HttpResponse::Ok().body("quota overridden"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/api/admin/quotas/{id}")]
+pub async fn reset_quota(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    auth: web::Data<AuthService>,
+    database: web::Data<DatabaseService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    match database.set_quota(id.into_inner(), None).await {
+        Ok(true) => HttpResponse::Ok().body("quota reset"),
+        Ok(false) => HttpResponse::NotFound().body("quota not overridden"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[post("/api/admin/gc")]
+pub async fn collect_garbage(
+    web::Header(token): web::Header<AccessToken>,
+    web::Query(GcRequest { dry_run }): web::Query<GcRequest>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    match avatars.collect_garbage(dry_run).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/api/admin/metrics")]
+pub async fn metrics(web::Header(token): web::Header<AccessToken>, auth: web::Data<AuthService>) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    HttpResponse::Ok().json(Metrics::snapshot())
+}
+
+#[get("/api/admin/subscribers/{id}")]
+pub async fn list_subscribers(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    auth: web::Data<AuthService>,
+) -> HttpResponse {
+    if auth.check_admin(token.0).is_none() {
+        return forbidden()
+    }
+
+    HttpResponse::Ok().json(hub::subscribers(id.into_inner()))
+}
@@ -1,4 +1,5 @@
 use actix_web::{
+    delete,
     get,
     http::StatusCode,
     web,
@@ -41,6 +42,18 @@ pub async fn refresh_access_token(
     }
 }
 
+#[delete("/api/auth")]
+pub async fn revoke_access_token(
+    web::Header(token): web::Header<AccessToken>,
+    auth: web::Data<AuthService>,
+) -> impl Responder {
+    if auth.revoke_access_token(token.0) {
+        ("logged out".to_string(), StatusCode::OK)
+    } else {
+        ("invalid or expired access token".to_string(), StatusCode::UNAUTHORIZED)
+    }
+}
+
 #[get("/api/auth/id")]
 pub async fn assign_server_id(
     web::Query(Username { username }): web::Query<Username>,
@@ -56,8 +69,8 @@ pub async fn obtain_access_token(
     auth: web::Data<AuthService>,
 ) -> impl Responder {
     match auth.obtain_access_token(&req, id).await {
-        Ok(Some(token)) => (encode_uuid(token), StatusCode::OK),
-        Ok(None) => ("invalid server ID".to_string(), StatusCode::UNAUTHORIZED),
+        Ok(Ok(token)) => (encode_uuid(token), StatusCode::OK),
+        Ok(Err(e)) => (e.to_string(), e.status()),
         Err(e) => (e.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
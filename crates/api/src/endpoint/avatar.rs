@@ -0,0 +1,277 @@
+use actix_web::{
+    delete,
+    get,
+    http::header::{
+        ETag,
+        EntityTag,
+        IfNoneMatch,
+    },
+    put,
+    web,
+    HttpResponse,
+};
+use futures_util::StreamExt;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    endpoint::header::AccessToken,
+    service::{
+        auth::AuthService,
+        avatar::{
+            AvatarService,
+            QuotaExceeded,
+            RestoreRejected,
+            UploadRejected,
+        },
+    },
+    storage::AvatarHash,
+};
+
+#[derive(Serialize)]
+pub struct Profile {
+    pub uuid: Uuid,
+    pub equipped: Vec<Equipped>,
+}
+
+#[derive(Serialize)]
+pub struct Equipped {
+    pub id: &'static str,
+    pub owner: Uuid,
+    /// SHA-256 of the avatar, so clients may skip downloading avatars they already have cached.
+    pub hash: AvatarHash,
+}
+
+#[derive(Serialize)]
+pub struct QuotaError {
+    #[serde(flatten)]
+    pub quota: QuotaExceeded,
+    pub message: String,
+}
+
+#[inline]
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized().body("invalid or expired access token")
+}
+
+#[inline]
+fn quota_exceeded(quota: QuotaExceeded) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(QuotaError {
+        message: quota.to_string(),
+        quota,
+    })
+}
+
+#[get("/api/{id}")]
+pub async fn profile(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    if auth.check_access_token(token.0).is_none() {
+        return unauthorized()
+    }
+
+    let id = id.into_inner();
+    match avatars.hash(id).await {
+        Ok(hash) => HttpResponse::Ok().json(Profile {
+            uuid: id,
+            equipped: hash
+                .map(|hash| Equipped {
+                    id: "avatar",
+                    owner: id,
+                    hash,
+                })
+                .into_iter()
+                .collect(),
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/api/{id}/avatar")]
+pub async fn download_avatar(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    cached: Option<web::Header<IfNoneMatch>>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    if auth.check_access_token(token.0).is_none() {
+        return unauthorized()
+    }
+
+    match avatars.download(id.into_inner()).await {
+        Ok(Some((hash, data))) => {
+            let tag = EntityTag::new_strong(hash.to_string());
+            match cached {
+                Some(web::Header(IfNoneMatch::Items(tags))) if tags.iter().any(|other| other.strong_eq(&tag)) => {
+                    HttpResponse::NotModified().insert_header(ETag(tag)).finish()
+                }
+                _ => HttpResponse::Ok().insert_header(ETag(tag)).body(data),
+            }
+        }
+        Ok(None) => HttpResponse::NotFound().body("no avatar equipped"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/api/{id}/avatar/info")]
+pub async fn avatar_info(
+    web::Header(token): web::Header<AccessToken>,
+    id: web::Path<Uuid>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    if auth.check_access_token(token.0).is_none() {
+        return unauthorized()
+    }
+
+    match avatars.details(id.into_inner()).await {
+        Ok(Some(details)) => HttpResponse::Ok().json(details),
+        Ok(None) => HttpResponse::NotFound().body("no avatar equipped"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("/api/avatar")]
+pub async fn upload_avatar(
+    web::Header(token): web::Header<AccessToken>,
+    mut payload: web::Payload,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    let Some(user_id) = auth.check_access_token(token.0) else {
+        return unauthorized()
+    };
+
+    let quota = match avatars.quota(user_id).await {
+        Ok(quota) => quota,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+        };
+
+        if (data.len() + chunk.len()) as u64 > quota.max_avatar_size {
+            return quota_exceeded(QuotaExceeded::AvatarSize {
+                limit: quota.max_avatar_size,
+            })
+        }
+
+        data.extend_from_slice(&chunk);
+    }
+
+    match avatars.upload(user_id, quota, data.freeze()).await {
+        Ok(Ok(hash)) => HttpResponse::Ok().body(hash.to_string()),
+        Ok(Err(UploadRejected::Invalid(e))) => HttpResponse::BadRequest().body(e.to_string()),
+        Ok(Err(UploadRejected::Quota(e))) => quota_exceeded(e),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/api/avatar")]
+pub async fn delete_avatar(
+    web::Header(token): web::Header<AccessToken>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    let Some(user_id) = auth.check_access_token(token.0) else {
+        return unauthorized()
+    };
+
+    match avatars.remove(user_id).await {
+        Ok(true) => HttpResponse::Ok().body("avatar removed"),
+        Ok(false) => HttpResponse::NotFound().body("no avatar equipped"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/api/avatar/history")]
+pub async fn avatar_history(
+    web::Header(token): web::Header<AccessToken>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    let Some(user_id) = auth.check_access_token(token.0) else {
+        return unauthorized()
+    };
+
+    match avatars.history(user_id).await {
+        Ok(history) => HttpResponse::Ok().json(history),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[put("/api/avatar/history/{version}")]
+pub async fn restore_avatar(
+    web::Header(token): web::Header<AccessToken>,
+    version: web::Path<u64>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    let Some(user_id) = auth.check_access_token(token.0) else {
+        return unauthorized()
+    };
+
+    let quota = match avatars.quota(user_id).await {
+        Ok(quota) => quota,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    match avatars.restore(user_id, quota, version.into_inner()).await {
+        Ok(Ok(hash)) => HttpResponse::Ok().body(hash.to_string()),
+        Ok(Err(RestoreRejected::NotFound)) => HttpResponse::NotFound().body("no such version"),
+        Ok(Err(RestoreRejected::Quota(e))) => quota_exceeded(e),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/api/avatar/history/{version}")]
+pub async fn forget_avatar(
+    web::Header(token): web::Header<AccessToken>,
+    version: web::Path<u64>,
+    auth: web::Data<AuthService>,
+    avatars: web::Data<AvatarService>,
+) -> HttpResponse {
+    let Some(user_id) = auth.check_access_token(token.0) else {
+        return unauthorized()
+    };
+
+    match avatars.forget(user_id, version.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().body("version forgotten"),
+        Ok(false) => HttpResponse::NotFound().body("no such version"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::to_bytes,
+        http::StatusCode,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn explains_exceeded_quotas() {
+        let res = quota_exceeded(QuotaExceeded::StorageBudget { limit: 1024 });
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body: serde_json::Value = serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "storage_budget",
+                "limit": 1024,
+                "message": "the server's avatar storage budget of 1024 bytes is exhausted",
+            })
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use actix_web::{
+    http::StatusCode,
+    post,
+    web,
+    Responder,
+};
+
+use crate::{
+    cluster::{
+        self,
+        peer,
+    },
+    endpoint::header::ClusterSignature,
+};
+
+#[post("/api/cluster/events")]
+pub async fn events(
+    web::Header(ClusterSignature(signature)): web::Header<ClusterSignature>,
+    payload: web::Payload,
+) -> impl Responder {
+    // Peers split their batches to stay within the limit, rather than the default for extracting bytes.
+    let body = match payload.to_bytes_limited(peer::MAX_BATCH_SIZE).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return (e.to_string(), StatusCode::BAD_REQUEST),
+        Err(e) => return (e.to_string(), StatusCode::PAYLOAD_TOO_LARGE),
+    };
+
+    let Some(batch) = peer::verify(&signature, &body) else {
+        return (
+            "invalid, stale or replayed cluster batch".to_string(),
+            StatusCode::UNAUTHORIZED,
+        )
+    };
+
+    batch.events.into_iter().for_each(cluster::apply);
+    (String::new(), StatusCode::NO_CONTENT)
+}
@@ -1,4 +1,7 @@
-use std::str::FromStr;
+use std::{
+    convert::Infallible,
+    str::FromStr,
+};
 
 use actix_web::{
     error::ParseError,
@@ -8,7 +11,10 @@ use actix_web::{
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::encode_uuid;
+use crate::{
+    cluster::peer,
+    encode_uuid,
+};
 
 pub struct UserAgent {
     pub name: String,
@@ -81,3 +87,31 @@ impl FromStr for AccessToken {
         Ok(Self(Uuid::try_parse(s)?))
     }
 }
+
+/// Base64-encoded signature of a cluster event batch.
+pub struct ClusterSignature(pub String);
+impl header::TryIntoHeaderValue for ClusterSignature {
+    type Error = header::InvalidHeaderValue;
+
+    fn try_into_value(self) -> Result<header::HeaderValue, Self::Error> {
+        header::HeaderValue::from_str(&self.0)
+    }
+}
+
+impl header::Header for ClusterSignature {
+    fn name() -> header::HeaderName {
+        header::HeaderName::from_static(peer::SIGNATURE)
+    }
+
+    fn parse<M: HttpMessage>(msg: &M) -> Result<Self, ParseError> {
+        header::from_one_raw_str(msg.headers().get(Self::name()))
+    }
+}
+
+impl FromStr for ClusterSignature {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
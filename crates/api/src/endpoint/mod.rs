@@ -1,4 +1,7 @@
+pub mod admin;
 pub mod auth;
+pub mod avatar;
+pub mod cluster;
 pub mod header;
 pub mod socket;
 
@@ -11,7 +14,26 @@ pub fn config(config: &mut web::ServiceConfig) {
     config
         .default_service(web::to(HttpResponse::NotFound))
         .service(auth::refresh_access_token)
+        .service(auth::revoke_access_token)
         .service(auth::assign_server_id)
         .service(auth::obtain_access_token)
-        .service(socket::web_socket);
+        .service(avatar::upload_avatar)
+        .service(avatar::delete_avatar)
+        .service(avatar::avatar_history)
+        .service(avatar::restore_avatar)
+        .service(avatar::forget_avatar)
+        .service(avatar::avatar_info)
+        .service(avatar::download_avatar)
+        .service(avatar::profile)
+        .service(socket::web_socket)
+        .service(cluster::events)
+        .service(admin::list_bans)
+        .service(admin::add_ban)
+        .service(admin::remove_ban)
+        .service(admin::get_quota)
+        .service(admin::set_quota)
+        .service(admin::reset_quota)
+        .service(admin::collect_garbage)
+        .service(admin::metrics)
+        .service(admin::list_subscribers);
 }
@@ -1,15 +1,201 @@
 use actix_web::{
+    http::header::Header,
     web,
     HttpRequest,
-    Responder,
+    HttpResponse,
 };
+use serde::Deserialize;
+use uuid::Uuid;
 
 use crate::{
-    service::auth::AuthService,
-    socket::actor::Socket,
+    endpoint::header::AccessToken,
+    service::{
+        auth::AuthService,
+        ban::BanService,
+        socket::SocketService,
+    },
+    socket::{
+        actor::Socket,
+        state::State,
+    },
 };
 
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    /// Parsed by hand rather than by the extractor, so malformed tokens are rejected the same way as unknown ones.
+    pub token: Option<String>,
+}
+
+/// Upgrades to a socket connection. The access token may be supplied here either through the `token` header or query
+/// parameter instead of through [`C2S::Token`](crate::socket::message::C2S::Token).
 #[actix_web::get("/ws")]
-pub async fn web_socket(req: HttpRequest, stream: web::Payload, auth: web::Data<AuthService>) -> impl Responder {
-    Socket::start(auth.into_inner(), &req, stream)
+pub async fn web_socket(
+    req: HttpRequest,
+    stream: web::Payload,
+    web::Query(TokenQuery { token }): web::Query<TokenQuery>,
+    auth: web::Data<AuthService>,
+    bans: web::Data<BanService>,
+    service: web::Data<SocketService>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let token = match req.headers().get(AccessToken::name()) {
+        Some(header) => Some(header.to_str().ok().and_then(|token| Uuid::try_parse(token).ok())),
+        None => token.map(|token| Uuid::try_parse(&token).ok()),
+    };
+
+    let state = match token {
+        Some(token) => {
+            let Some((token, user_id)) = token.and_then(|token| Some((token, auth.check_access_token(token)?))) else {
+                return Ok(HttpResponse::Unauthorized().body("invalid access token"))
+            };
+
+            if let Some(ban) = bans.check(user_id) {
+                return Ok(HttpResponse::Forbidden().body(format!("banned: {}", ban.reason)))
+            }
+
+            State::Authenticated { user_id, token }
+        }
+        None => State::AwaitingToken,
+    };
+
+    Socket::start(
+        auth.into_inner(),
+        bans.get_ref().clone(),
+        service.into_inner(),
+        state,
+        &req,
+        stream,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::TcpListener,
+        num::NonZeroUsize,
+        sync::Arc,
+        time::Duration,
+    };
+
+    use actix_http::ws::Frame;
+    use actix_web::{
+        http::StatusCode,
+        rt::{
+            spawn,
+            time::timeout,
+        },
+        test,
+        App,
+        HttpServer,
+    };
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::{
+        random_uuid,
+        service::{
+            auth::import_access_token,
+            database::DatabaseService,
+            socket::SocketConfig,
+        },
+        socket::actor::SOCKETS,
+    };
+
+    async fn services() -> (web::Data<AuthService>, web::Data<BanService>, web::Data<SocketService>) {
+        let database = DatabaseService::open_in_memory().unwrap();
+        let bans = BanService::open(database.clone(), None).await.unwrap();
+        (
+            web::Data::new(AuthService::new(
+                Duration::from_secs(10),
+                Duration::from_secs(600),
+                NonZeroUsize::new(8).unwrap(),
+                Arc::default(),
+                None,
+                bans.clone(),
+                database,
+            )),
+            web::Data::new(bans),
+            web::Data::new(SocketService::new(SocketConfig {
+                max_connections: NonZeroUsize::new(4).unwrap(),
+                heartbeat_interval: Duration::from_secs(10),
+                idle_timeout: Duration::from_secs(30),
+                auth_timeout: Duration::from_secs(10),
+                max_message_size: 64 * 1024,
+                json_debug: false,
+                outbound_high_water: 256 * 1024,
+                outbound_limit: 1024 * 1024,
+                max_subscriptions: 512,
+                compression: false,
+                compression_threshold: 64,
+            })),
+        )
+    }
+
+    async fn upgrade(req: test::TestRequest) -> StatusCode {
+        let (auth, bans, sockets) = services().await;
+        let app = test::init_service(App::new().app_data(auth).app_data(bans).app_data(sockets).service(web_socket)).await;
+
+        test::call_service(&app, req.to_request()).await.status()
+    }
+
+    #[actix_web::test]
+    async fn upgrades_with_valid_tokens() {
+        let (auth, bans, sockets) = services().await;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(auth.clone())
+                .app_data(bans.clone())
+                .app_data(sockets.clone())
+                .service(web_socket)
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        spawn(server);
+
+        let client = awc::Client::new();
+        for by_header in [true, false] {
+            let (token, user_id) = (random_uuid(), random_uuid());
+            import_access_token(token, Uuid::nil(), user_id, String::new(), 0, Duration::ZERO);
+
+            let req = if by_header {
+                client.ws(format!("ws://{addr}/ws")).header("token", token.to_string())
+            } else {
+                client.ws(format!("ws://{addr}/ws?token={token}"))
+            };
+            let (res, mut conn) = req.connect().await.unwrap();
+            assert_eq!(res.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+            // Sockets are only acknowledged once registered.
+            let frame = timeout(Duration::from_secs(5), conn.next())
+                .await
+                .expect("the socket wasn't acknowledged")
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame, Frame::Binary(web::Bytes::from_static(&[0])));
+            assert!(SOCKETS.read().get(&user_id).is_some_and(|addrs| addrs[0].0 == token));
+        }
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_header_tokens() {
+        let malformed = test::TestRequest::get().uri("/ws").insert_header(("token", "not-a-uuid"));
+        assert_eq!(upgrade(malformed).await, StatusCode::UNAUTHORIZED);
+
+        let unknown = test::TestRequest::get()
+            .uri("/ws")
+            .insert_header(("token", random_uuid().to_string()));
+        assert_eq!(upgrade(unknown).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn rejects_bad_query_tokens() {
+        let malformed = test::TestRequest::get().uri("/ws?token=not-a-uuid");
+        assert_eq!(upgrade(malformed).await, StatusCode::UNAUTHORIZED);
+
+        let unknown = test::TestRequest::get().uri(&format!("/ws?token={}", random_uuid()));
+        assert_eq!(upgrade(unknown).await, StatusCode::UNAUTHORIZED);
+    }
 }
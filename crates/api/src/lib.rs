@@ -6,9 +6,13 @@ pub use awc;
 pub use log;
 pub use uuid;
 
+pub mod cluster;
 pub mod endpoint;
+pub mod metrics;
+pub mod nbt;
 pub mod service;
 pub mod socket;
+pub mod storage;
 
 use std::{
     self,
@@ -18,8 +22,20 @@ use std::{
     },
     io::BufRead,
     net::SocketAddr,
-    sync::Arc,
-    time::Duration,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
 };
 
 use actix_web::{
@@ -56,10 +72,28 @@ use uuid::{
     Uuid,
 };
 
-use crate::service::{
-    auth::AuthService,
-    http::HttpService,
-    ServiceLocator,
+use crate::{
+    cluster::ClusterConfig,
+    service::{
+        allow::AllowList,
+        auth::AuthService,
+        avatar::{
+            AvatarConfig,
+            AvatarService,
+        },
+        ban::BanService,
+        database::DatabaseService,
+        http::HttpService,
+        socket::{
+            SocketConfig,
+            SocketService,
+        },
+        ServiceLocator,
+    },
+    storage::{
+        fs::FsStorage,
+        Storage,
+    },
 };
 
 pub type FxHashMap<K, V> = HashMap<K, V, FxBuildHasher>;
@@ -79,6 +113,35 @@ pub fn encode_uuid(uuid: Uuid) -> String {
     uuid.as_hyphenated().encode_lower(&mut [0; Hyphenated::LENGTH]).to_string()
 }
 
+#[inline]
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Logs requests like [`Logger::default`], but leaves out query strings, as the socket endpoint accepts access tokens
+/// in them.
+fn logger() -> Logger {
+    Logger::new(r#"%a "%{request}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#).custom_request_replace("request", |req| {
+        format!("{} {} {:?}", req.method(), req.path(), req.version())
+    })
+}
+
+/// Certificate authorities trusted by the operating system.
+pub fn native_roots() -> RootCertStore {
+    let mut store = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            store.add_parsable_certificates(certs);
+        }
+        Err(e) => log::error!("couldn't read native certificate roots: {e}"),
+    }
+
+    store
+}
+
 pub struct Backend<Key: AsReader, Cert: AsReader> {
     pub port: u16,
     pub key: Key,
@@ -86,6 +149,22 @@ pub struct Backend<Key: AsReader, Cert: AsReader> {
 
     pub server_id_timeout: Duration,
     pub access_timeout: Duration,
+    pub max_sessions: NonZeroUsize,
+    pub socket: SocketConfig,
+
+    pub admins: Vec<Uuid>,
+    /// Legacy JSON ban list, imported into the database once if it exists.
+    pub bans: PathBuf,
+    pub allow_list: Option<PathBuf>,
+    /// How often the allow-list is checked for modifications.
+    pub allow_list_interval: Duration,
+    pub cluster: Option<ClusterConfig>,
+    /// SQLite database file metadata is persisted to.
+    pub database: PathBuf,
+
+    /// Directory avatars are stored in.
+    pub avatars: PathBuf,
+    pub avatar: AvatarConfig,
 
     pub configs: Vec<Box<dyn BackendConfig>>,
 }
@@ -98,6 +177,16 @@ impl<Key: AsReader, Cert: AsReader> Backend<Key, Cert> {
             mut cert,
             server_id_timeout,
             access_timeout,
+            max_sessions,
+            socket,
+            admins,
+            bans,
+            allow_list,
+            allow_list_interval,
+            cluster,
+            database,
+            avatars,
+            avatar,
             configs,
         } = self;
 
@@ -120,26 +209,39 @@ impl<Key: AsReader, Cert: AsReader> Backend<Key, Cert> {
             .with_no_client_auth()
             .with_single_cert(certs, PrivateKeyDer::from(key))?;
 
+        let admins = Arc::new(admins.into_iter().collect::<FxHashSet<_>>());
+        let database = DatabaseService::open(database)?;
+        let bans = BanService::open(database.clone(), Some(&bans)).await?;
+        let allow_list = match allow_list {
+            Some(path) => {
+                let list = AllowList::load(path)?;
+                list.watch(allow_list_interval);
+                Some(list)
+            }
+            None => None,
+        };
+
+        if let Some(config) = cluster {
+            cluster::start(config, access_timeout).await?;
+        }
+
+        let storage: Arc<dyn Storage> = Arc::new(FsStorage::new(avatars)?);
+
         let configs = Arc::new(configs);
+        let gc_started = Arc::new(AtomicBool::new(false));
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
         let server = HttpServer::new(move || {
             let client_config = ClientConfig::builder()
-                .with_root_certificates({
-                    let mut store = RootCertStore::empty();
-                    match rustls_native_certs::load_native_certs() {
-                        Ok(certs) => {
-                            store.add_parsable_certificates(certs);
-                        }
-                        Err(e) => log::error!("couldn't read native certificate roots: {e}"),
-                    }
-
-                    store
-                })
+                .with_root_certificates(native_roots())
                 .with_no_client_auth();
 
             struct Locator {
                 auth: AuthService,
+                avatar: AvatarService,
+                ban: BanService,
+                database: DatabaseService,
                 http: HttpService,
+                socket: SocketService,
             }
 
             impl ServiceLocator for Locator {
@@ -147,8 +249,16 @@ impl<Key: AsReader, Cert: AsReader> Backend<Key, Cert> {
                 fn locate_dyn(&mut self, id: TypeId) -> anyhow::Result<&mut dyn Any> {
                     if id == TypeId::of::<AuthService>() {
                         Ok(&mut self.auth)
+                    } else if id == TypeId::of::<AvatarService>() {
+                        Ok(&mut self.avatar)
+                    } else if id == TypeId::of::<BanService>() {
+                        Ok(&mut self.ban)
+                    } else if id == TypeId::of::<DatabaseService>() {
+                        Ok(&mut self.database)
                     } else if id == TypeId::of::<HttpService>() {
                         Ok(&mut self.http)
+                    } else if id == TypeId::of::<SocketService>() {
+                        Ok(&mut self.socket)
                     } else {
                         anyhow::bail!("invalid service")
                     }
@@ -156,19 +266,42 @@ impl<Key: AsReader, Cert: AsReader> Backend<Key, Cert> {
             }
 
             let mut locator = Locator {
-                auth: AuthService::new(server_id_timeout, access_timeout),
+                auth: AuthService::new(
+                    server_id_timeout,
+                    access_timeout,
+                    max_sessions,
+                    admins.clone(),
+                    allow_list.clone(),
+                    bans.clone(),
+                    database.clone(),
+                ),
+                avatar: AvatarService::new(storage.clone(), database.clone(), avatar.clone()),
+                ban: bans.clone(),
+                database: database.clone(),
                 http: HttpService::new(client_config),
+                socket: SocketService::new(socket.clone()),
             };
 
             for config in &*configs {
                 config.config(&mut locator);
             }
 
+            // Only one worker sweeps in the background, through whichever storage the configs settled on.
+            if let Some(interval) = locator.avatar.config().gc_interval {
+                if !gc_started.swap(true, Ordering::Relaxed) {
+                    locator.avatar.clone().collect_garbage_every(interval);
+                }
+            }
+
             App::new()
                 .wrap(NormalizePath::trim())
-                .wrap(Logger::default())
+                .wrap(logger())
                 .app_data(web::Data::new(locator.auth))
+                .app_data(web::Data::new(locator.avatar))
+                .app_data(web::Data::new(locator.ban))
+                .app_data(web::Data::new(locator.database))
                 .app_data(web::Data::new(locator.http))
+                .app_data(web::Data::new(locator.socket))
                 .configure(endpoint::config)
         });
 
@@ -0,0 +1,63 @@
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+
+use serde::Serialize;
+
+pub struct Counter(AtomicU64);
+impl Counter {
+    #[inline]
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline]
+    pub fn add(&self, amount: u64) {
+        self.0.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frames dropped instead of being delivered to sockets whose outbound queue exceeded the high-water mark.
+pub static FRAMES_DROPPED: Counter = Counter::new();
+/// Sockets disconnected because their outbound queue exceeded the hard limit.
+pub static SOCKETS_THROTTLED: Counter = Counter::new();
+/// Bytes saved by compressing outgoing socket messages with `permessage-deflate`.
+pub static DEFLATE_BYTES_SAVED: Counter = Counter::new();
+/// Bytes saved by clients compressing incoming socket messages with `permessage-deflate`.
+pub static INFLATE_BYTES_SAVED: Counter = Counter::new();
+/// Cluster events dropped instead of being sent, as their destination fell too far behind or kept refusing them.
+pub static CLUSTER_EVENTS_DROPPED: Counter = Counter::new();
+
+#[derive(Serialize)]
+pub struct Metrics {
+    pub frames_dropped: u64,
+    pub sockets_throttled: u64,
+    pub deflate_bytes_saved: u64,
+    pub inflate_bytes_saved: u64,
+    pub cluster_events_dropped: u64,
+}
+
+impl Metrics {
+    pub fn snapshot() -> Self {
+        Self {
+            frames_dropped: FRAMES_DROPPED.get(),
+            sockets_throttled: SOCKETS_THROTTLED.get(),
+            deflate_bytes_saved: DEFLATE_BYTES_SAVED.get(),
+            inflate_bytes_saved: INFLATE_BYTES_SAVED.get(),
+            cluster_events_dropped: CLUSTER_EVENTS_DROPPED.get(),
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Reader for Minecraft's Named Binary Tag format, which Figura avatars are serialized in. Every length is checked
+//! against the remaining input before allocating, and the tree's nesting and tag count are bounded, so hostile input
+//! can't make it allocate or recurse unboundedly.
+
+use std::{
+    collections::BTreeMap,
+    io::{
+        self,
+        Read,
+    },
+    mem::size_of,
+};
+
+use flate2::read::GzDecoder;
+use thiserror::Error;
+
+const END: u8 = 0;
+const BYTE: u8 = 1;
+const SHORT: u8 = 2;
+const INT: u8 = 3;
+const LONG: u8 = 4;
+const FLOAT: u8 = 5;
+const DOUBLE: u8 = 6;
+const BYTE_ARRAY: u8 = 7;
+const STRING: u8 = 8;
+const LIST: u8 = 9;
+const COMPOUND: u8 = 10;
+const INT_ARRAY: u8 = 11;
+const LONG_ARRAY: u8 = 12;
+
+pub type Compound = BTreeMap<String, Tag>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(Compound),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    #[inline]
+    pub fn as_compound(&self) -> Option<&Compound> {
+        match self {
+            Self::Compound(compound) => Some(compound),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn as_byte_array(&self) -> Option<&[u8]> {
+        match self {
+            Self::ByteArray(array) => Some(array),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    /// Deepest nesting of lists and compounds, counting the root.
+    pub max_depth: usize,
+    /// Most tags in the whole tree, counting each array as one.
+    pub max_elements: usize,
+    /// Largest size in bytes of the uncompressed tree.
+    pub max_size: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum NbtError {
+    #[error("couldn't decompress: {0}")]
+    Decompress(#[from] io::Error),
+    #[error("uncompressed data exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("unexpected end of data")]
+    UnexpectedEnd,
+    #[error("trailing data after the root tag")]
+    Trailing,
+    #[error("unknown tag type {0}")]
+    UnknownTag(u8),
+    #[error("negative length")]
+    NegativeLength,
+    #[error("nested deeper than {0} levels")]
+    TooDeep(usize),
+    #[error("more than {0} tags")]
+    TooManyElements(usize),
+    #[error("root tag isn't a compound")]
+    RootNotCompound,
+}
+
+/// Decompresses gzip-compressed data, failing as soon as it exceeds `max_size` bytes.
+pub fn decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>, NbtError> {
+    let mut uncompressed = Vec::new();
+    GzDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut uncompressed)?;
+
+    if uncompressed.len() > max_size {
+        Err(NbtError::TooLarge(max_size))
+    } else {
+        Ok(uncompressed)
+    }
+}
+
+/// Reads a gzip-compressed tree, as written by Minecraft's `NbtIo.writeCompressed`, returning its root compound.
+#[inline]
+pub fn read_compressed(data: &[u8], limits: &Limits) -> Result<Compound, NbtError> {
+    read(&decompress(data, limits.max_size)?, limits)
+}
+
+/// Reads an uncompressed tree, returning its root compound.
+pub fn read(data: &[u8], limits: &Limits) -> Result<Compound, NbtError> {
+    if data.len() > limits.max_size {
+        return Err(NbtError::TooLarge(limits.max_size))
+    }
+
+    let mut reader = Reader {
+        data,
+        limits,
+        elements: 0,
+    };
+
+    if reader.u8()? != COMPOUND {
+        return Err(NbtError::RootNotCompound)
+    }
+
+    // The root's name is always empty in practice, and meaningless either way.
+    reader.string()?;
+    reader.count()?;
+    let root = reader.compound(1)?;
+
+    if !reader.data.is_empty() {
+        return Err(NbtError::Trailing)
+    }
+
+    Ok(root)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    limits: &'a Limits,
+    elements: usize,
+}
+
+macro_rules! read_num {
+    ($($name:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[inline]
+            fn $name(&mut self) -> Result<$ty, NbtError> {
+                let bytes = self.take(size_of::<$ty>())?;
+                Ok(<$ty>::from_be_bytes(bytes.try_into().expect("length checked by `take`")))
+            }
+        )*
+    };
+}
+
+impl<'a> Reader<'a> {
+    read_num! {
+        u8: u8,
+        i8: i8,
+        u16: u16,
+        i16: i16,
+        i32: i32,
+        i64: i64,
+        f32: f32,
+        f64: f64,
+    }
+
+    #[inline]
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NbtError> {
+        if self.data.len() < len {
+            return Err(NbtError::UnexpectedEnd)
+        }
+
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    #[inline]
+    fn len(&mut self) -> Result<usize, NbtError> {
+        usize::try_from(self.i32()?).map_err(|_| NbtError::NegativeLength)
+    }
+
+    /// Takes an array of `len` elements of `size` bytes each, failing before allocating if there isn't enough input.
+    #[inline]
+    fn array(&mut self, size: usize) -> Result<&'a [u8], NbtError> {
+        let len = self.len()?;
+        self.take(len.checked_mul(size).ok_or(NbtError::UnexpectedEnd)?)
+    }
+
+    #[inline]
+    fn count(&mut self) -> Result<(), NbtError> {
+        self.elements += 1;
+        if self.elements > self.limits.max_elements {
+            Err(NbtError::TooManyElements(self.limits.max_elements))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn string(&mut self) -> Result<String, NbtError> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+
+        // Java writes "modified" UTF-8, which only differs in how it encodes NUL and supplementary characters.
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn tag(&mut self, id: u8, depth: usize) -> Result<Tag, NbtError> {
+        self.count()?;
+        Ok(match id {
+            BYTE => Tag::Byte(self.i8()?),
+            SHORT => Tag::Short(self.i16()?),
+            INT => Tag::Int(self.i32()?),
+            LONG => Tag::Long(self.i64()?),
+            FLOAT => Tag::Float(self.f32()?),
+            DOUBLE => Tag::Double(self.f64()?),
+            BYTE_ARRAY => Tag::ByteArray(self.array(1)?.to_vec()),
+            STRING => Tag::String(self.string()?),
+            LIST => Tag::List(self.list(depth)?),
+            COMPOUND => Tag::Compound(self.compound(depth)?),
+            INT_ARRAY => Tag::IntArray(
+                self.array(4)?
+                    .chunks_exact(4)
+                    .map(|chunk| i32::from_be_bytes(chunk.try_into().expect("chunks are exact")))
+                    .collect(),
+            ),
+            LONG_ARRAY => Tag::LongArray(
+                self.array(8)?
+                    .chunks_exact(8)
+                    .map(|chunk| i64::from_be_bytes(chunk.try_into().expect("chunks are exact")))
+                    .collect(),
+            ),
+            id => return Err(NbtError::UnknownTag(id)),
+        })
+    }
+
+    fn list(&mut self, depth: usize) -> Result<Vec<Tag>, NbtError> {
+        if depth > self.limits.max_depth {
+            return Err(NbtError::TooDeep(self.limits.max_depth))
+        }
+
+        let id = self.u8()?;
+        let len = self.len()?;
+        if id == END {
+            // Empty lists are written with the end tag as their element type; anything else can't be represented.
+            return if len == 0 {
+                Ok(Vec::new())
+            } else {
+                Err(NbtError::UnknownTag(END))
+            }
+        }
+
+        // Every other element takes at least a byte, so the remaining input bounds the capacity.
+        let mut list = Vec::with_capacity(len.min(self.data.len()));
+        for _ in 0..len {
+            list.push(self.tag(id, depth + 1)?);
+        }
+
+        Ok(list)
+    }
+
+    fn compound(&mut self, depth: usize) -> Result<Compound, NbtError> {
+        if depth > self.limits.max_depth {
+            return Err(NbtError::TooDeep(self.limits.max_depth))
+        }
+
+        let mut compound = Compound::new();
+        loop {
+            let id = self.u8()?;
+            if id == END {
+                break Ok(compound)
+            }
+
+            let name = self.string()?;
+            let tag = self.tag(id, depth + 1)?;
+            compound.insert(name, tag);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_depth: 4,
+        max_elements: 16,
+        max_size: 1024,
+    };
+
+    fn named(id: u8, name: &str) -> Vec<u8> {
+        let mut data = vec![id];
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data
+    }
+
+    #[test]
+    fn reads_compound() {
+        let mut data = named(COMPOUND, "");
+        data.extend(named(STRING, "name"));
+        data.extend_from_slice(&[0, 2, b'h', b'i']);
+        data.extend(named(BYTE_ARRAY, "bytes"));
+        data.extend_from_slice(&[0, 0, 0, 2, 1, 2]);
+        data.extend(named(LIST, "list"));
+        data.extend_from_slice(&[INT, 0, 0, 0, 1, 0, 0, 0, 7]);
+        data.push(END);
+
+        let root = read(&data, &LIMITS).unwrap();
+        assert_eq!(root["name"], Tag::String("hi".to_string()));
+        assert_eq!(root["bytes"], Tag::ByteArray(vec![1, 2]));
+        assert_eq!(root["list"], Tag::List(vec![Tag::Int(7)]));
+    }
+
+    #[test]
+    fn rejects_hostile_trees() {
+        // Nested deeper than allowed.
+        let mut data = named(COMPOUND, "");
+        for _ in 0..LIMITS.max_depth {
+            data.extend(named(COMPOUND, "a"));
+        }
+        data.extend(vec![END; LIMITS.max_depth + 1]);
+        assert!(matches!(read(&data, &LIMITS), Err(NbtError::TooDeep(..))));
+
+        // Too many tags.
+        let mut data = named(COMPOUND, "");
+        data.extend(named(LIST, "a"));
+        data.extend_from_slice(&[BYTE, 0, 0, 0, 32]);
+        data.extend_from_slice(&[0; 32]);
+        data.push(END);
+        assert!(matches!(read(&data, &LIMITS), Err(NbtError::TooManyElements(..))));
+
+        // Claims far more elements than there is input.
+        let mut data = named(COMPOUND, "");
+        data.extend(named(LONG_ARRAY, "a"));
+        data.extend_from_slice(&i32::MAX.to_be_bytes());
+        assert!(matches!(read(&data, &LIMITS), Err(NbtError::UnexpectedEnd)));
+
+        // Not a compound at all.
+        assert!(matches!(read(&named(STRING, ""), &LIMITS), Err(NbtError::RootNotCompound)));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+use actix_web::rt::{
+    spawn,
+    task::JoinHandle,
+    time::sleep,
+};
+use parking_lot::RwLock;
+use uuid::Uuid;
+
+use crate::{
+    service::auth,
+    FxHashSet,
+};
+
+#[derive(Default)]
+struct Entries {
+    ids: FxHashSet<Uuid>,
+    names: FxHashSet<String>,
+}
+
+impl Entries {
+    fn parse(list: &str) -> Self {
+        let mut entries = Self::default();
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            match Uuid::try_parse(line) {
+                Ok(id) => entries.ids.insert(id),
+                Err(..) => entries.names.insert(line.to_lowercase()),
+            };
+        }
+
+        entries
+    }
+}
+
+/// Users permitted to authenticate in private server mode, listed by UUID or username one per line in a text file.
+/// Blank lines and lines starting with `#` are ignored, and usernames are matched case-insensitively.
+pub struct AllowList {
+    path: PathBuf,
+    entries: RwLock<Entries>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl AllowList {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Arc<Self>> {
+        let list = Arc::new(Self {
+            path: path.as_ref().to_path_buf(),
+            entries: RwLock::new(Entries::default()),
+            modified: RwLock::new(None),
+        });
+
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Re-reads the file if it has been modified since it was last read, returning whether it was.
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        if modified.is_some() && modified == *self.modified.read() {
+            return Ok(false)
+        }
+
+        let entries = Entries::parse(&fs::read_to_string(&self.path)?);
+
+        log::info!(
+            "Loaded {} UUIDs and {} usernames from allow-list `{}`.",
+            entries.ids.len(),
+            entries.names.len(),
+            self.path.display()
+        );
+
+        *self.entries.write() = entries;
+        *self.modified.write() = modified;
+        Ok(true)
+    }
+
+    /// Periodically reloads the file until the list is deallocated, revoking the access tokens of users it no longer
+    /// allows.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let list = Arc::downgrade(self);
+        spawn(async move {
+            loop {
+                sleep(interval).await;
+
+                let Some(list) = list.upgrade() else { break };
+                match list.reload() {
+                    Ok(true) => {
+                        let revoked = auth::revoke_where(|user_id, name| !list.allows(user_id, name));
+                        if revoked > 0 {
+                            log::info!("Revoked {revoked} access tokens of users no longer on the allow-list.");
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => log::error!("Couldn't reload allow-list `{}`: {e}", list.path.display()),
+                }
+            }
+        })
+    }
+
+    pub fn allows(&self, user_id: Uuid, name: &str) -> bool {
+        let entries = self.entries.read();
+        entries.ids.contains(&user_id) || entries.names.contains(&name.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::Write,
+    };
+
+    use super::*;
+
+    #[test]
+    fn parses_entries() {
+        let entries = Entries::parse("# Staff\n\n  Notch  \n00000000-0000-0000-0000-000000000001\n#jeb_\n");
+        assert_eq!(entries.ids.len(), 1);
+        assert!(entries.ids.contains(&Uuid::from_u128(1)));
+        assert_eq!(entries.names.len(), 1);
+        assert!(entries.names.contains("notch"));
+    }
+
+    #[test]
+    fn reloads_when_modified() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("allow.txt");
+        fs::write(&path, "Notch\n")?;
+
+        let list = AllowList::load(&path)?;
+        assert!(list.allows(Uuid::from_u128(1), "notch"));
+        assert!(!list.reload()?);
+
+        let mut file = File::create(&path)?;
+        file.write_all(b"jeb_\n")?;
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))?;
+
+        assert!(list.reload()?);
+        assert!(!list.allows(Uuid::from_u128(1), "Notch"));
+        assert!(list.allows(Uuid::from_u128(1), "JEB_"));
+        Ok(())
+    }
+}
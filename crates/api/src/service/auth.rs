@@ -1,9 +1,11 @@
 use std::{
+    num::NonZeroUsize,
     sync::Arc,
     time::Duration,
 };
 
 use actix_web::{
+    http::StatusCode,
     rt::{
         spawn,
         task::JoinHandle,
@@ -16,12 +18,26 @@ use actix_web::{
 };
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
+use thiserror::Error;
 use uuid::Uuid;
 
 use crate::{
-    encode_uuid,
+    cluster::{
+        self,
+        Event,
+    },
     random_uuid,
-    service::Service,
+    service::{
+        allow::AllowList,
+        ban::BanService,
+        database::DatabaseService,
+        Service,
+    },
+    socket::{
+        actor::Socket,
+        message::WsCode,
+    },
+    unix_now,
     FxHashMap,
     FxHashSet,
 };
@@ -31,28 +47,167 @@ struct Token {
     server_id: Uuid,
     user_id: Uuid,
     name: String,
-    auth: Arc<dyn Auth>,
+    /// Index of the authenticator the token was issued by, configured identically across workers and instances.
+    auth: usize,
 }
 
 pub struct AuthService {
     auths: Vec<Arc<dyn Auth>>,
     server_ids: Arc<RwLock<FxHashSet<Uuid>>>,
     access_tokens: Arc<RwLock<FxHashSet<Uuid>>>,
+    max_sessions: NonZeroUsize,
+    admins: Arc<FxHashSet<Uuid>>,
+    allow_list: Option<Arc<AllowList>>,
+    bans: BanService,
+    database: DatabaseService,
     checker: JoinHandle<()>,
 }
 
 impl Service for AuthService {}
 
+#[derive(Error, Debug)]
+pub enum AccessDenied {
+    #[error("invalid server ID")]
+    InvalidServerId,
+    #[error("banned: {0}")]
+    Banned(String),
+    #[error("this is a private server and you're not on its allow-list")]
+    NotAllowed,
+}
+
+impl AccessDenied {
+    #[inline]
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidServerId => StatusCode::UNAUTHORIZED,
+            Self::Banned(..) | Self::NotAllowed => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl Token {
+    /// Closes every socket authenticated with this token, prompting their clients to re-authenticate.
+    #[inline]
+    fn expel(&self, id: Uuid, reason: &str) {
+        Socket::kick_token(self.user_id, id, WsCode::ReAuth, reason);
+    }
+}
+
 pub trait Auth: 'static + Send + Sync {
+    /// Name of the authentication provider, recorded alongside users it authenticates.
+    fn provider(&self) -> &str;
+
     fn authenticate(&self, req: &HttpRequest, username: &str, server_id: Uuid) -> JoinHandle<anyhow::Result<Option<Uuid>>>;
 }
 
-static SERVER_IDS: Lazy<Arc<RwLock<FxHashMap<Uuid, (Instant, String)>>>> = Lazy::new(Default::default);
-static ACCESS_TOKENS: Lazy<Arc<RwLock<FxHashMap<Uuid, Arc<Token>>>>> = Lazy::new(Default::default);
+type ServerIds = FxHashMap<Uuid, (Instant, String)>;
+type AccessTokens = FxHashMap<Uuid, Arc<Token>>;
+
+static SERVER_IDS: Lazy<Arc<RwLock<ServerIds>>> = Lazy::new(Default::default);
+static ACCESS_TOKENS: Lazy<Arc<RwLock<AccessTokens>>> = Lazy::new(Default::default);
+/// Access tokens issued by other instances of the cluster, expired by whichever service's checker gets to them first.
+static IMPORTED: Lazy<RwLock<FxHashSet<Uuid>>> = Lazy::new(Default::default);
+
+/// Removes the access token if it's past its timeout, returning whether it should still be tracked.
+fn check_expiry(id: Uuid, now: Instant, access_timeout: Duration) -> bool {
+    let time = ACCESS_TOKENS.read().get(&id).map(|token| *token.time.read());
+    match time {
+        Some(time) if now - time >= access_timeout => {
+            // Sockets are kicked outside the lock, as registering them checks tokens under the socket lock.
+            let removed = ACCESS_TOKENS.write().remove(&id);
+            if let Some(token) = removed {
+                token.expel(id, "access token expired");
+            }
+            false
+        }
+        Some(..) => true,
+        None => false,
+    }
+}
+
+/// Accepts an access token issued by another instance of the cluster. `age` is how long ago it was issued or last
+/// refreshed, so it times out along with the rest of the cluster instead of starting over.
+pub fn import_access_token(token: Uuid, server_id: Uuid, user_id: Uuid, name: String, auth: usize, age: Duration) {
+    let now = Instant::now();
+    ACCESS_TOKENS.write().insert(
+        token,
+        Arc::new(Token {
+            time: RwLock::new(now.checked_sub(age).unwrap_or(now)),
+            server_id,
+            user_id,
+            name,
+            auth,
+        }),
+    );
+
+    IMPORTED.write().insert(token);
+}
+
+/// Returns the access tokens imported from other instances of the cluster.
+pub fn imported_access_tokens() -> Vec<Uuid> {
+    IMPORTED.read().iter().copied().collect()
+}
+
+/// Postpones the access token's timeout after another instance of the cluster refreshed it.
+pub fn touch_access_token(access_token: Uuid) {
+    if let Some(token) = ACCESS_TOKENS.read().get(&access_token) {
+        *token.time.write() = Instant::now();
+    }
+}
+
+/// Like [`AuthService::revoke_access_token`], without telling the rest of the cluster.
+pub fn revoke_access_token_locally(access_token: Uuid) -> bool {
+    let Some(token) = ({ ACCESS_TOKENS.write().remove(&access_token) }) else {
+        return false
+    };
+
+    token.expel(access_token, "access token revoked");
+    true
+}
+
+/// Like [`AuthService::revoke_user`], without telling the rest of the cluster.
+pub fn revoke_user_locally(user_id: Uuid) -> usize {
+    let revoked = {
+        let mut tokens = ACCESS_TOKENS.write();
+        let len = tokens.len();
+
+        tokens.retain(|_, token| token.user_id != user_id);
+        len - tokens.len()
+    };
+
+    Socket::kick(user_id, WsCode::ReAuth, "access tokens revoked");
+    revoked
+}
+
+/// Revokes every access token whose user and username match the predicate throughout the cluster, returning how many
+/// were revoked.
+pub fn revoke_where(predicate: impl Fn(Uuid, &str) -> bool) -> usize {
+    let revoked = {
+        let mut tokens = ACCESS_TOKENS.write();
+        tokens
+            .extract_if(|_, token| predicate(token.user_id, &token.name))
+            .collect::<Vec<_>>()
+    };
+
+    for (id, token) in &revoked {
+        token.expel(*id, "no longer allowed");
+        cluster::broadcast(Event::TokenRevoked { token: *id });
+    }
+
+    revoked.len()
+}
 
 impl AuthService {
     #[inline]
-    pub fn new(server_id_timeout: Duration, access_timeout: Duration) -> Self {
+    pub fn new(
+        server_id_timeout: Duration,
+        access_timeout: Duration,
+        max_sessions: NonZeroUsize,
+        admins: Arc<FxHashSet<Uuid>>,
+        allow_list: Option<Arc<AllowList>>,
+        bans: BanService,
+        database: DatabaseService,
+    ) -> Self {
         let auths = Vec::new();
         let server_ids = Arc::new(RwLock::new(FxHashSet::default()));
         let access_tokens = Arc::new(RwLock::new(FxHashSet::default()));
@@ -66,35 +221,20 @@ impl AuthService {
                 while let (Some(server_ids), Some(access_tokens)) = (server_ids.upgrade(), access_tokens.upgrade()) {
                     let now = Instant::now();
 
-                    {
-                        server_ids.write().retain(|&id| {
-                            if let Some(&(time, ..)) = { SERVER_IDS.read().get(&id) } {
-                                if now - time >= server_id_timeout {
-                                    SERVER_IDS.write().remove(&id);
-                                    false
-                                } else {
-                                    true
-                                }
-                            } else {
+                    server_ids.write().retain(|&id| {
+                        let time = SERVER_IDS.read().get(&id).map(|&(time, ..)| time);
+                        match time {
+                            Some(time) if now - time >= server_id_timeout => {
+                                SERVER_IDS.write().remove(&id);
                                 false
                             }
-                        });
-                    }
+                            Some(..) => true,
+                            None => false,
+                        }
+                    });
 
-                    {
-                        access_tokens.write().retain(|&id| {
-                            if let Some(token) = { ACCESS_TOKENS.read().get(&id).cloned() } {
-                                if now - *token.time.read() >= access_timeout {
-                                    ACCESS_TOKENS.write().remove(&id);
-                                    false
-                                } else {
-                                    true
-                                }
-                            } else {
-                                false
-                            }
-                        });
-                    }
+                    access_tokens.write().retain(|&id| check_expiry(id, now, access_timeout));
+                    IMPORTED.write().retain(|&id| check_expiry(id, now, access_timeout));
 
                     sleep(Duration::from_secs(1)).await;
                 }
@@ -105,6 +245,11 @@ impl AuthService {
             auths,
             server_ids,
             access_tokens,
+            max_sessions,
+            admins,
+            allow_list,
+            bans,
+            database,
             checker,
         }
     }
@@ -117,41 +262,119 @@ impl AuthService {
     pub fn assign_server_id(&self, username: &str) -> Uuid {
         let server_id = random_uuid();
         SERVER_IDS.write().insert(server_id, (Instant::now(), username.to_string()));
+        self.server_ids.write().insert(server_id);
         server_id
     }
 
-    pub async fn obtain_access_token(&self, req: &HttpRequest, server_id: Uuid) -> anyhow::Result<Option<Uuid>> {
+    pub async fn obtain_access_token(
+        &self,
+        req: &HttpRequest,
+        server_id: Uuid,
+    ) -> anyhow::Result<Result<Uuid, AccessDenied>> {
         let Some((.., name)) = ({ SERVER_IDS.write().remove(&server_id) }) else {
-            return Ok(None)
+            return Ok(Err(AccessDenied::InvalidServerId))
         };
 
-        for auth in &self.auths {
+        for (index, auth) in self.auths.iter().enumerate() {
             match auth.authenticate(req, &name, server_id).await? {
                 Ok(Some(user_id)) => {
+                    if let Some(ban) = self.bans.check(user_id) {
+                        return Ok(Err(AccessDenied::Banned(ban.reason)))
+                    }
+
+                    if self.allow_list.as_ref().is_some_and(|list| !list.allows(user_id, &name)) {
+                        return Ok(Err(AccessDenied::NotAllowed))
+                    }
+
+                    if let Err(e) = self
+                        .database
+                        .see_user(user_id, name.clone(), auth.provider().to_string(), unix_now())
+                        .await
+                    {
+                        log::warn!("Couldn't record user {user_id}: {e}");
+                    }
+
                     let token = random_uuid();
-                    ACCESS_TOKENS.write().insert(
+                    cluster::broadcast(Event::TokenIssued {
                         token,
-                        Arc::new(Token {
-                            time: RwLock::new(Instant::now()),
-                            server_id,
-                            user_id,
-                            name,
-                            auth: auth.clone(),
-                        }),
-                    );
-
-                    return Ok(Some(token))
+                        server_id,
+                        user_id,
+                        name: name.clone(),
+                        auth: index,
+                    });
+
+                    let evicted = {
+                        let mut tokens = ACCESS_TOKENS.write();
+                        tokens.insert(
+                            token,
+                            Arc::new(Token {
+                                time: RwLock::new(Instant::now()),
+                                server_id,
+                                user_id,
+                                name,
+                                auth: index,
+                            }),
+                        );
+
+                        // Revoke the least recently refreshed sessions of this user if they hold too many, never
+                        // the one just issued.
+                        let mut sessions = tokens
+                            .iter()
+                            .filter(|&(&id, other)| id != token && other.user_id == user_id)
+                            .map(|(&id, other)| (id, *other.time.read()))
+                            .collect::<Vec<_>>();
+
+                        let excess = (sessions.len() + 1).saturating_sub(self.max_sessions.get());
+                        sessions.sort_unstable_by_key(|&(.., time)| time);
+                        sessions[..excess]
+                            .iter()
+                            .filter_map(|&(id, ..)| tokens.remove(&id).map(|evicted| (id, evicted)))
+                            .collect::<Vec<_>>()
+                    };
+
+                    // Sockets are kicked outside the lock, as registering them checks tokens under the socket lock.
+                    for (id, evicted) in evicted {
+                        evicted.expel(id, "too many sessions");
+                        cluster::broadcast(Event::TokenRevoked { token: id });
+                    }
+
+                    self.access_tokens.write().insert(token);
+                    return Ok(Ok(token))
                 }
                 Ok(None) => {}
                 Err(e) => log::error!("Couldn't authenticate {name}: {e}"),
             }
         }
 
-        Ok(None)
+        Ok(Err(AccessDenied::InvalidServerId))
+    }
+
+    /// Returns the user ID the access token was issued to, if it's still valid.
+    pub fn check_access_token(&self, access_token: Uuid) -> Option<Uuid> {
+        ACCESS_TOKENS.read().get(&access_token).map(|token| token.user_id)
+    }
+
+    /// Returns the user ID the access token was issued to, if it's still valid and the user is an administrator.
+    pub fn check_admin(&self, access_token: Uuid) -> Option<Uuid> {
+        self.check_access_token(access_token)
+            .filter(|user_id| self.admins.contains(user_id))
+    }
+
+    /// Invalidates the access token before its timeout, returning `false` if it was already invalid.
+    pub fn revoke_access_token(&self, access_token: Uuid) -> bool {
+        let revoked = revoke_access_token_locally(access_token);
+        if revoked {
+            cluster::broadcast(Event::TokenRevoked { token: access_token });
+        }
+
+        revoked
     }
 
-    pub fn check_access_token(&self, access_token: Uuid) -> bool {
-        ACCESS_TOKENS.read().contains_key(&access_token)
+    /// Invalidates every access token issued to the user and closes their open sockets, returning how many tokens
+    /// were revoked.
+    pub fn revoke_user(&self, user_id: Uuid) -> usize {
+        cluster::broadcast(Event::UserRevoked { user_id });
+        revoke_user_locally(user_id)
     }
 
     pub async fn refresh_access_token(&self, req: &HttpRequest, access_token: Uuid) -> anyhow::Result<bool> {
@@ -159,8 +382,16 @@ impl AuthService {
             return Ok(false)
         };
 
-        if token
-            .auth
+        let Some(auth) = self.auths.get(token.auth) else {
+            log::error!(
+                "Access token of {} was issued by unknown authenticator #{}.",
+                token.name,
+                token.auth
+            );
+            return Ok(false)
+        };
+
+        if auth
             .authenticate(req, &token.name, token.server_id)
             .await?
             .unwrap_or_else(|e| {
@@ -170,6 +401,10 @@ impl AuthService {
             .is_some()
         {
             *token.time.write() = Instant::now();
+            cluster::broadcast(Event::TokenRefreshed {
+                token: access_token,
+                user_id: token.user_id,
+            });
             Ok(true)
         } else {
             Ok(false)
@@ -190,3 +425,62 @@ impl Drop for AuthService {
         self.checker.abort();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        random_uuid,
+        socket::actor::SOCKETS,
+    };
+
+    #[test]
+    fn imported_tokens_keep_their_age() {
+        let (fresh, stale) = (Uuid::from_u128(41), Uuid::from_u128(42));
+        let access_timeout = Duration::from_secs(60);
+        import_access_token(fresh, Uuid::nil(), Uuid::nil(), String::new(), 0, Duration::from_secs(30));
+        import_access_token(stale, Uuid::nil(), Uuid::nil(), String::new(), 0, Duration::from_secs(90));
+
+        let now = Instant::now();
+        assert!(check_expiry(fresh, now, access_timeout));
+        assert!(!check_expiry(stale, now, access_timeout));
+        assert!(!check_expiry(fresh, now + Duration::from_secs(30), access_timeout));
+    }
+
+    #[test]
+    fn expires_tokens_while_sockets_register() {
+        let access_timeout = Duration::from_secs(60);
+        let (done, finished) = mpsc::channel();
+
+        let expiring = done.clone();
+        thread::spawn(move || {
+            for _ in 0..100_000 {
+                let (token, user_id) = (random_uuid(), random_uuid());
+                import_access_token(token, Uuid::nil(), user_id, String::new(), 0, access_timeout);
+                SOCKETS.write().insert(user_id, Vec::new());
+                assert!(!check_expiry(token, Instant::now(), access_timeout));
+            }
+            expiring.send(()).unwrap();
+        });
+
+        // Registering a socket checks its token under the socket lock.
+        thread::spawn(move || {
+            for _ in 0..100_000 {
+                let _sockets = SOCKETS.write();
+                ACCESS_TOKENS.read().get(&random_uuid());
+            }
+            done.send(()).unwrap();
+        });
+
+        for _ in 0..2 {
+            finished
+                .recv_timeout(Duration::from_secs(10))
+                .expect("expiring tokens deadlocked with registering sockets");
+        }
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{
+    rt::{
+        spawn,
+        task::JoinHandle,
+        time::sleep,
+    },
+    web,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    cluster::{
+        self,
+        Event,
+    },
+    nbt::{
+        self,
+        Compound,
+        Limits,
+        NbtError,
+        Tag,
+    },
+    service::{
+        database::{
+            AvatarMeta,
+            AvatarVersion,
+            DatabaseService,
+        },
+        Service,
+    },
+    socket::{
+        hub,
+        message::S2C,
+    },
+    storage::{
+        gc::{
+            self,
+            GcReport,
+        },
+        AvatarHash,
+        Storage,
+    },
+    unix_now,
+};
+
+/// How much larger than the upload limit an avatar may get once decompressed.
+const MAX_EXPANSION: usize = 32;
+/// Deepest nesting of an avatar's tree; model groups are nested, but never this deep in practice.
+const MAX_DEPTH: usize = 128;
+/// Most tags in an avatar's tree.
+const MAX_ELEMENTS: usize = 1 << 18;
+
+#[derive(Error, Debug)]
+pub enum InvalidAvatar {
+    #[error("malformed avatar: {0}")]
+    Malformed(#[from] NbtError),
+    #[error("avatar is missing its `metadata` compound")]
+    MissingMetadata,
+    #[error("avatar field `{0}` has the wrong type")]
+    WrongType(String),
+}
+
+/// Metadata Figura writes into an avatar's tree.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AvatarInfo {
+    pub name: String,
+    pub authors: String,
+    pub version: String,
+    /// Size in bytes of each script, keyed by its name.
+    pub scripts: BTreeMap<String, u64>,
+}
+
+#[inline]
+fn limits(max_size: usize) -> Limits {
+    Limits {
+        max_depth: MAX_DEPTH,
+        max_elements: MAX_ELEMENTS,
+        max_size: max_size.saturating_mul(MAX_EXPANSION),
+    }
+}
+
+impl AvatarInfo {
+    /// Validates the gzip-compressed avatar, whose compressed size must already be within `max_size`, and extracts
+    /// its metadata.
+    #[inline]
+    pub fn parse(data: &[u8], max_size: usize) -> Result<Self, InvalidAvatar> {
+        Self::from_root(&nbt::read_compressed(data, &limits(max_size))?)
+    }
+
+    fn from_root(root: &Compound) -> Result<Self, InvalidAvatar> {
+        let metadata = root
+            .get("metadata")
+            .ok_or(InvalidAvatar::MissingMetadata)?
+            .as_compound()
+            .ok_or_else(|| InvalidAvatar::WrongType("metadata".to_string()))?;
+
+        let string = |key: &str| match metadata.get(key) {
+            None => Ok(String::new()),
+            Some(tag) => tag
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| InvalidAvatar::WrongType(format!("metadata.{key}"))),
+        };
+
+        let scripts = match root.get("scripts") {
+            None => BTreeMap::new(),
+            Some(Tag::Compound(scripts)) => scripts
+                .iter()
+                .map(|(name, script)| match script.as_byte_array() {
+                    Some(script) => Ok((name.clone(), script.len() as u64)),
+                    None => Err(InvalidAvatar::WrongType(format!("scripts.{name}"))),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(..) => return Err(InvalidAvatar::WrongType("scripts".to_string())),
+        };
+
+        for key in ["textures", "models", "sounds"] {
+            if root.get(key).is_some_and(|tag| tag.as_compound().is_none()) {
+                return Err(InvalidAvatar::WrongType(key.to_string()))
+            }
+        }
+
+        Ok(Self {
+            name: string("name")?,
+            authors: string("authors")?,
+            version: string("version")?,
+            scripts,
+        })
+    }
+}
+
+/// Breakdown of what an avatar contains.
+#[derive(Serialize, Clone, Debug)]
+pub struct AvatarDetails {
+    pub owner: Uuid,
+    pub hash: AvatarHash,
+    /// Size in bytes as stored, i.e. compressed.
+    pub compressed_size: u64,
+    /// Size in bytes once decompressed.
+    pub size: u64,
+    pub texture_count: usize,
+    pub model_count: usize,
+    pub script_count: usize,
+    pub sound_count: usize,
+    #[serde(flatten)]
+    pub info: AvatarInfo,
+}
+
+impl AvatarDetails {
+    pub fn parse(owner: Uuid, hash: AvatarHash, data: &[u8], max_size: usize) -> Result<Self, InvalidAvatar> {
+        let limits = limits(max_size);
+        let uncompressed = nbt::decompress(data, limits.max_size)?;
+        let root = nbt::read(&uncompressed, &limits)?;
+        let info = AvatarInfo::from_root(&root)?;
+
+        let compound = |key: &str| root.get(key).and_then(Tag::as_compound);
+        Ok(Self {
+            owner,
+            hash,
+            compressed_size: data.len() as u64,
+            size: uncompressed.len() as u64,
+            // Texture images are keyed by name in `src`, next to their per-texture settings.
+            texture_count: compound("textures")
+                .and_then(|textures| textures.get("src"))
+                .and_then(Tag::as_compound)
+                .map_or(0, Compound::len),
+            // Each model file is a child of the root model part.
+            model_count: compound("models")
+                .and_then(|models| models.get("chld"))
+                .map_or(0, |children| match children {
+                    Tag::List(children) => children.len(),
+                    _ => 0,
+                }),
+            script_count: info.scripts.len(),
+            sound_count: compound("sounds").map_or(0, Compound::len),
+            info,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct AvatarConfig {
+    /// Largest avatar in bytes users may upload, unless overridden.
+    pub max_size: u64,
+    /// How many avatar slots a single user may take up, unless overridden. Each distinct blob their current avatar or
+    /// a past upload is stored in takes up one, and uploads needing another slot past that are refused until the user
+    /// forgets some of their history.
+    pub max_avatars: u64,
+    /// Total bytes all stored avatars may take up, or `None` if unlimited.
+    pub storage_budget: Option<u64>,
+    /// How often orphaned blobs are swept in the background, or `None` if only on demand.
+    pub gc_interval: Option<Duration>,
+    /// How long blobs are kept after they're last stored, even if orphaned.
+    pub gc_grace: Duration,
+    /// How many of each user's latest uploads are kept to roll back to; `0` keeps none.
+    pub history: usize,
+}
+
+/// Per-user overrides of the configured limits, e.g. for donors. See [`AvatarConfig`] for what they limit.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuotaOverride {
+    pub max_avatar_size: Option<u64>,
+    pub max_avatars: Option<u64>,
+}
+
+/// Limits in effect for a user.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub max_avatar_size: u64,
+    pub max_avatars: u64,
+}
+
+/// Limits in effect for a user, along with how much of them they use up.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QuotaUsage {
+    #[serde(flatten)]
+    pub quota: Quota,
+    /// Avatar slots the user takes up.
+    pub avatars: u64,
+}
+
+#[derive(Error, Serialize, Debug)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum QuotaExceeded {
+    #[error("avatars may be at most {limit} bytes")]
+    AvatarSize { limit: u64 },
+    #[error("users may store at most {limit} avatars, counting those in their history")]
+    AvatarCount { limit: u64 },
+    #[error("the server's avatar storage budget of {limit} bytes is exhausted")]
+    StorageBudget { limit: u64 },
+}
+
+#[derive(Error, Debug)]
+pub enum UploadRejected {
+    #[error(transparent)]
+    Invalid(#[from] InvalidAvatar),
+    #[error(transparent)]
+    Quota(#[from] QuotaExceeded),
+}
+
+#[derive(Error, Debug)]
+pub enum RestoreRejected {
+    #[error("no such version")]
+    NotFound,
+    #[error(transparent)]
+    Quota(#[from] QuotaExceeded),
+}
+
+#[derive(Clone)]
+pub struct AvatarService {
+    storage: Arc<dyn Storage>,
+    database: DatabaseService,
+    config: AvatarConfig,
+}
+
+impl Service for AvatarService {}
+
+impl AvatarService {
+    #[inline]
+    pub fn new(storage: Arc<dyn Storage>, database: DatabaseService, config: AvatarConfig) -> Self {
+        Self {
+            storage,
+            database,
+            config,
+        }
+    }
+
+    /// Replaces where avatars are stored.
+    #[inline]
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = storage;
+    }
+
+    #[inline]
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    #[inline]
+    pub fn config(&self) -> &AvatarConfig {
+        &self.config
+    }
+
+    /// Returns the limits in effect for the user, taking their overrides into account.
+    pub async fn quota(&self, user_id: Uuid) -> anyhow::Result<Quota> {
+        let quota = self.database.quota(user_id).await?.unwrap_or_default();
+        Ok(Quota {
+            max_avatar_size: quota.max_avatar_size.unwrap_or(self.config.max_size),
+            max_avatars: quota.max_avatars.unwrap_or(self.config.max_avatars),
+        })
+    }
+
+    /// Returns the limits in effect for the user along with how many avatar slots they take up.
+    pub async fn usage(&self, user_id: Uuid) -> anyhow::Result<QuotaUsage> {
+        Ok(QuotaUsage {
+            quota: self.quota(user_id).await?,
+            avatars: self.database.avatar_count(user_id).await?,
+        })
+    }
+
+    /// Tells subscribers of the owner, in this instance and the rest of the cluster, to fetch their avatar again.
+    pub fn notify(owner: Uuid) {
+        hub::publish(owner, S2C::Event(owner.as_u128()));
+        cluster::broadcast(Event::AvatarChanged { owner });
+    }
+
+    /// Returns the hash of the user's current avatar, if any.
+    pub async fn hash(&self, user_id: Uuid) -> anyhow::Result<Option<AvatarHash>> {
+        self.storage.reference(user_id).await?
+    }
+
+    /// Returns the user's current avatar along with its hash, if any.
+    pub async fn download(&self, user_id: Uuid) -> anyhow::Result<Option<(AvatarHash, web::Bytes)>> {
+        let Some(hash) = self.hash(user_id).await? else {
+            return Ok(None)
+        };
+
+        Ok(self.storage.get(hash).await??.map(|data| (hash, data)))
+    }
+
+    /// Breaks down the user's current avatar, if any.
+    pub async fn details(&self, user_id: Uuid) -> anyhow::Result<Option<AvatarDetails>> {
+        let Some((hash, data)) = self.download(user_id).await? else {
+            return Ok(None)
+        };
+
+        // The avatar was within its owner's limit when uploaded, which may since have been lowered.
+        let max_size = data.len().max(self.config.max_size as usize);
+        Ok(Some(
+            web::block(move || AvatarDetails::parse(user_id, hash, &data, max_size)).await??,
+        ))
+    }
+
+    /// Validates and stores the avatar within the user's quota and equips it as their current one, returning its
+    /// hash.
+    pub async fn upload(
+        &self,
+        user_id: Uuid,
+        quota: Quota,
+        data: web::Bytes,
+    ) -> anyhow::Result<Result<AvatarHash, UploadRejected>> {
+        let hash = AvatarHash::of(&data);
+        let size = data.len() as u64;
+        if size > quota.max_avatar_size {
+            return Ok(Err(QuotaExceeded::AvatarSize {
+                limit: quota.max_avatar_size,
+            }
+            .into()))
+        }
+
+        if let Err(e) = self.check_count(user_id, quota, hash).await? {
+            return Ok(Err(e.into()))
+        }
+
+        // Blobs are shared between identical avatars, so only new ones count towards the budget.
+        if let Some(limit) = self.config.storage_budget {
+            if !self.database.has_blob(hash).await? && self.database.storage_used().await? + size > limit {
+                return Ok(Err(QuotaExceeded::StorageBudget { limit }.into()))
+            }
+        }
+
+        let info = {
+            let (data, max_size) = (data.clone(), quota.max_avatar_size as usize);
+            match web::block(move || AvatarInfo::parse(&data, max_size)).await? {
+                Ok(info) => info,
+                Err(e) => return Ok(Err(e.into())),
+            }
+        };
+
+        let avatar = AvatarMeta {
+            owner: user_id,
+            hash,
+            size,
+            uploaded: unix_now(),
+            info,
+        };
+
+        let _blob = gc::hold(hash).await;
+        self.storage.put(hash, data).await??;
+        if self.config.history > 0 {
+            self.database.push_version(avatar.clone(), self.config.history).await?;
+        }
+
+        self.equip(avatar).await?;
+        Ok(Ok(hash))
+    }
+
+    /// Lists the user's latest uploads, newest first.
+    #[inline]
+    pub async fn history(&self, user_id: Uuid) -> anyhow::Result<Vec<AvatarVersion>> {
+        self.database.versions(user_id).await
+    }
+
+    /// Equips one of the user's latest uploads as their current avatar again, returning its hash.
+    pub async fn restore(
+        &self,
+        user_id: Uuid,
+        quota: Quota,
+        version: u64,
+    ) -> anyhow::Result<Result<AvatarHash, RestoreRejected>> {
+        let Some(avatar) = self.database.version(user_id, version).await? else {
+            return Ok(Err(RestoreRejected::NotFound))
+        };
+
+        // The blob already takes up one of the user's slots, but their quota may since have been lowered.
+        if self.database.avatar_count(user_id).await? > quota.max_avatars {
+            return Ok(Err(QuotaExceeded::AvatarCount {
+                limit: quota.max_avatars,
+            }
+            .into()))
+        }
+
+        let hash = avatar.hash;
+        self.equip(avatar).await?;
+        Ok(Ok(hash))
+    }
+
+    /// Forgets one of the user's latest uploads, freeing its avatar slot unless the blob is still in use by another
+    /// version or their current avatar. Returns `false` if there's no such version.
+    #[inline]
+    pub async fn forget(&self, user_id: Uuid, version: u64) -> anyhow::Result<bool> {
+        self.database.forget_version(user_id, version).await
+    }
+
+    /// Fails if uploading the blob would take up more avatar slots than the user's quota allows.
+    async fn check_count(&self, user_id: Uuid, quota: Quota, hash: AvatarHash) -> anyhow::Result<Result<(), QuotaExceeded>> {
+        let count = self.database.avatar_count_after(user_id, hash, self.config.history).await?;
+        Ok(if count > quota.max_avatars {
+            Err(QuotaExceeded::AvatarCount {
+                limit: quota.max_avatars,
+            })
+        } else {
+            Ok(())
+        })
+    }
+
+    /// Points the owner at the already stored avatar and tells their subscribers.
+    async fn equip(&self, avatar: AvatarMeta) -> anyhow::Result<()> {
+        let owner = avatar.owner;
+        self.storage.set_reference(owner, Some(avatar.hash)).await??;
+        self.database.set_avatar(owner, Some(avatar)).await?;
+
+        Self::notify(owner);
+        Ok(())
+    }
+
+    /// Unequips the user's current avatar, returning `false` if they didn't have one.
+    pub async fn remove(&self, user_id: Uuid) -> anyhow::Result<bool> {
+        if self.hash(user_id).await?.is_none() {
+            return Ok(false)
+        }
+
+        self.storage.set_reference(user_id, None).await??;
+        self.database.set_avatar(user_id, None).await?;
+
+        Self::notify(user_id);
+        Ok(true)
+    }
+
+    /// Sweeps orphaned blobs past their grace period, or only reports them if `dry_run` is set.
+    pub async fn collect_garbage(&self, dry_run: bool) -> anyhow::Result<GcReport> {
+        gc::sweep(&*self.storage, &self.database, self.config.gc_grace, dry_run).await
+    }
+
+    /// Sweeps orphaned blobs every `interval` on the current arbiter.
+    pub fn collect_garbage_every(self, interval: Duration) -> JoinHandle<()> {
+        spawn(async move {
+            loop {
+                sleep(interval).await;
+                match self.collect_garbage(false).await {
+                    Ok(GcReport {
+                        swept, reclaimed_bytes, ..
+                    }) if !swept.is_empty() => {
+                        log::info!(
+                            "Swept {} orphaned avatar blobs, reclaiming {reclaimed_bytes} bytes.",
+                            swept.len()
+                        )
+                    }
+                    Ok(..) => {}
+                    Err(e) => log::error!("Couldn't sweep orphaned avatar blobs: {e}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::GzEncoder,
+        Compression,
+    };
+
+    use super::*;
+    use crate::{
+        random_uuid,
+        storage::fs::FsStorage,
+    };
+
+    /// Builds a compressed avatar named `name`.
+    fn avatar(name: &str) -> web::Bytes {
+        let mut data = vec![10, 0, 0, 10, 0, 8];
+        data.extend_from_slice(b"metadata");
+        data.extend_from_slice(&[8, 0, 4]);
+        data.extend_from_slice(b"name");
+        data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&[0, 0]);
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    fn service(dir: &tempfile::TempDir, storage_budget: Option<u64>) -> AvatarService {
+        AvatarService::new(
+            Arc::new(FsStorage::new(dir.path()).unwrap()),
+            DatabaseService::open_in_memory().unwrap(),
+            AvatarConfig {
+                max_size: 1024,
+                max_avatars: 2,
+                storage_budget,
+                gc_interval: None,
+                gc_grace: Duration::from_secs(3600),
+                history: 5,
+            },
+        )
+    }
+
+    #[actix_web::test]
+    async fn enforces_avatar_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let avatars = service(&dir, None);
+        let id = random_uuid();
+        let quota = avatars.quota(id).await.unwrap();
+
+        let [a, b, c] = ["a", "b", "c"].map(avatar);
+        for data in [&a, &b, &a] {
+            avatars.upload(id, quota, data.clone()).await.unwrap().unwrap();
+        }
+        assert_eq!(avatars.usage(id).await.unwrap().avatars, 2);
+
+        // Both slots are taken, so `c` is turned away until `b` is forgotten.
+        assert!(matches!(
+            avatars.upload(id, quota, c.clone()).await.unwrap(),
+            Err(UploadRejected::Quota(QuotaExceeded::AvatarCount { limit: 2 }))
+        ));
+        let history = avatars.history(id).await.unwrap();
+        assert_eq!(history[1].avatar.hash, AvatarHash::of(&b));
+        assert!(avatars.forget(id, history[1].id).await.unwrap());
+        assert!(!avatars.forget(id, history[1].id).await.unwrap());
+        assert_eq!(avatars.usage(id).await.unwrap().avatars, 1);
+
+        let hash = avatars.upload(id, quota, c).await.unwrap().unwrap();
+        assert_eq!(avatars.usage(id).await.unwrap().avatars, 2);
+
+        // Restoring takes up no more slots, but a lowered quota still turns users away.
+        let history = avatars.history(id).await.unwrap();
+        assert_eq!(history[0].avatar.hash, hash);
+        let lowered = Quota { max_avatars: 1, ..quota };
+        assert!(matches!(
+            avatars.restore(id, lowered, history[1].id).await.unwrap(),
+            Err(RestoreRejected::Quota(QuotaExceeded::AvatarCount { limit: 1 }))
+        ));
+        avatars.restore(id, quota, history[1].id).await.unwrap().unwrap();
+        assert_eq!(avatars.hash(id).await.unwrap(), Some(AvatarHash::of(&a)));
+        assert_eq!(avatars.usage(id).await.unwrap().avatars, 2);
+    }
+
+    #[actix_web::test]
+    async fn keeps_within_storage_budget() {
+        let a = avatar("a");
+        let dir = tempfile::tempdir().unwrap();
+        let avatars = service(&dir, Some(a.len() as u64));
+        let (first, second) = (random_uuid(), random_uuid());
+        let quota = avatars.quota(first).await.unwrap();
+
+        avatars.upload(first, quota, a.clone()).await.unwrap().unwrap();
+        assert!(matches!(
+            avatars.upload(second, quota, avatar("b")).await.unwrap(),
+            Err(UploadRejected::Quota(QuotaExceeded::StorageBudget { limit })) if limit == a.len() as u64
+        ));
+
+        // Identical avatars share a blob, so they fit in the budget.
+        avatars.upload(second, quota, a.clone()).await.unwrap().unwrap();
+        assert_eq!(avatars.hash(second).await.unwrap(), Some(AvatarHash::of(&a)));
+    }
+}
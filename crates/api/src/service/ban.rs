@@ -0,0 +1,204 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::Path,
+    sync::Arc,
+};
+
+use parking_lot::RwLock;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::{
+    service::{
+        database::DatabaseService,
+        Service,
+    },
+    socket::{
+        actor::Socket,
+        hub,
+        message::{
+            SubError,
+            WsCode,
+        },
+    },
+    unix_now,
+    FxHashMap,
+};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    pub id: Uuid,
+    pub reason: String,
+    /// UNIX timestamp in seconds after which the ban is lifted, or `None` if it's permanent.
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    #[inline]
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| now >= expires)
+    }
+}
+
+struct Bans {
+    db: DatabaseService,
+    list: RwLock<FxHashMap<Uuid, Ban>>,
+    /// Serializes writers, so the list only changes once the database did and readers never wait on it.
+    writing: Mutex<()>,
+}
+
+/// Ban list persisted to the database and cached in memory, shared across workers.
+#[derive(Clone)]
+pub struct BanService {
+    bans: Arc<Bans>,
+}
+
+impl Service for BanService {}
+
+impl BanService {
+    /// Loads the ban list from the database, forgetting expired bans. If the JSON file bans used to be kept in exists,
+    /// its bans are imported first and it's renamed with an `.imported` suffix, so that only happens once.
+    pub async fn open(db: DatabaseService, legacy: Option<&Path>) -> anyhow::Result<Self> {
+        if let Some(path) = legacy {
+            match fs::read(path) {
+                Ok(bytes) => {
+                    let bans = serde_json::from_slice::<Vec<Ban>>(&bytes)?;
+                    let imported = bans.len();
+                    for ban in bans {
+                        db.put_ban(ban).await?;
+                    }
+
+                    let mut renamed = path.as_os_str().to_owned();
+                    renamed.push(".imported");
+                    fs::rename(path, &renamed)?;
+                    log::info!("Imported {imported} bans from `{}`.", path.display());
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let now = unix_now();
+        let mut list = FxHashMap::default();
+        for ban in db.bans().await? {
+            if ban.is_expired(now) {
+                db.remove_ban(ban.id).await?;
+            } else {
+                list.insert(ban.id, ban);
+            }
+        }
+
+        Ok(Self {
+            bans: Arc::new(Bans {
+                db,
+                list: RwLock::new(list),
+                writing: Mutex::new(()),
+            }),
+        })
+    }
+
+    /// Returns the user's ban if it's still in effect.
+    pub fn check(&self, user_id: Uuid) -> Option<Ban> {
+        let now = unix_now();
+        self.bans
+            .list
+            .read()
+            .get(&user_id)
+            .filter(|ban| !ban.is_expired(now))
+            .cloned()
+    }
+
+    /// Returns every ban still in effect.
+    pub fn list(&self) -> Vec<Ban> {
+        let now = unix_now();
+        self.bans
+            .list
+            .read()
+            .values()
+            .filter(|ban| !ban.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Bans the user, closes their open sockets with [`WsCode::Banned`] and drops every subscription to them.
+    pub async fn add(&self, ban: Ban) -> anyhow::Result<()> {
+        let (id, reason) = (ban.id, format!("banned: {}", ban.reason));
+        {
+            let _writing = self.bans.writing.lock().await;
+            self.bans.db.put_ban(ban.clone()).await?;
+            self.bans.list.write().insert(id, ban);
+        }
+
+        Socket::kick(id, WsCode::Banned, &reason);
+        hub::drop_subscribers(id, SubError::Banned);
+        Ok(())
+    }
+
+    /// Lifts the user's ban, returning `false` if they weren't banned.
+    pub async fn remove(&self, user_id: Uuid) -> anyhow::Result<bool> {
+        let _writing = self.bans.writing.lock().await;
+        let removed = self.bans.db.remove_ban(user_id).await?;
+        self.bans.list.write().remove(&user_id);
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+
+    use super::*;
+
+    #[test]
+    fn imports_legacy_bans_once() -> anyhow::Result<()> {
+        System::new().block_on(async {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("bans.json");
+            let (banned, expired) = (Uuid::from_u128(1), Uuid::from_u128(2));
+            fs::write(
+                &path,
+                serde_json::to_vec(&[
+                    Ban {
+                        id: banned,
+                        reason: "griefing".to_string(),
+                        expires: None,
+                    },
+                    Ban {
+                        id: expired,
+                        reason: "spam".to_string(),
+                        expires: Some(1),
+                    },
+                ])?,
+            )?;
+
+            let db = DatabaseService::open_in_memory()?;
+            let bans = BanService::open(db.clone(), Some(&path)).await?;
+            assert_eq!(bans.check(banned).map(|ban| ban.reason).as_deref(), Some("griefing"));
+            assert!(bans.check(expired).is_none());
+            assert!(!path.exists());
+            assert!(dir.path().join("bans.json.imported").exists());
+
+            // Bans now live in the database, along with changes to them.
+            assert!(bans.remove(banned).await?);
+            assert!(!bans.remove(banned).await?);
+            bans.add(Ban {
+                id: expired,
+                reason: "spam again".to_string(),
+                expires: None,
+            })
+            .await?;
+
+            let bans = BanService::open(db, Some(&path)).await?;
+            assert!(bans.check(banned).is_none());
+            assert_eq!(bans.list().len(), 1);
+            assert_eq!(bans.check(expired).map(|ban| ban.reason).as_deref(), Some("spam again"));
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,669 @@
+//! SQLite-backed metadata repository. The schema is embedded in the binary and migrated forward on startup, with the
+//! applied version tracked in SQLite's `user_version`.
+
+use std::{
+    path::Path,
+    sync::Arc,
+};
+
+use actix_web::web;
+use parking_lot::Mutex;
+use rusqlite::{
+    params,
+    Connection,
+    OptionalExtension,
+    Row,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+use uuid::Uuid;
+
+use crate::{
+    service::{
+        avatar::{
+            AvatarInfo,
+            QuotaOverride,
+        },
+        ban::Ban,
+        Service,
+    },
+    storage::AvatarHash,
+};
+
+/// Schema migrations, applied in order. Never edit one that's been released; append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/0001_init.sql"),
+    include_str!("../../migrations/0002_avatar_info.sql"),
+    include_str!("../../migrations/0003_quotas.sql"),
+    include_str!("../../migrations/0004_avatar_history.sql"),
+];
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub id: Uuid,
+    /// Name the user last authenticated with.
+    pub name: String,
+    /// Authentication provider the user last authenticated through.
+    pub provider: String,
+    /// UNIX timestamps in seconds of the first and latest authentications.
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// A past upload, identified by `id` among every user's uploads.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AvatarVersion {
+    pub id: u64,
+    #[serde(flatten)]
+    pub avatar: AvatarMeta,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AvatarMeta {
+    pub owner: Uuid,
+    pub hash: AvatarHash,
+    /// Size in bytes.
+    pub size: u64,
+    /// UNIX timestamp in seconds.
+    pub uploaded: u64,
+    #[serde(flatten)]
+    pub info: AvatarInfo,
+}
+
+/// Persistent metadata shared across workers, behind a single connection.
+#[derive(Clone)]
+pub struct DatabaseService {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Service for DatabaseService {}
+
+impl DatabaseService {
+    /// Opens the database, creating it if it doesn't exist yet, and migrates it to the latest schema.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::migrate(conn)
+    }
+
+    /// Opens a database living only in memory, for tests.
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::migrate(Connection::open_in_memory()?)
+    }
+
+    fn migrate(mut conn: Connection) -> anyhow::Result<Self> {
+        let version = conn.pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))?;
+        if version > MIGRATIONS.len() {
+            anyhow::bail!(
+                "database schema version {version} is newer than the latest known {}",
+                MIGRATIONS.len()
+            );
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+
+            log::info!("Migrated database to schema version {}.", index + 1);
+        }
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs the query on a blocking thread.
+    async fn run<T: 'static + Send>(
+        &self,
+        query: impl 'static + Send + FnOnce(&mut Connection) -> rusqlite::Result<T>,
+    ) -> anyhow::Result<T> {
+        let conn = self.conn.clone();
+        Ok(web::block(move || query(&mut conn.lock())).await??)
+    }
+
+    /// Records an authentication of the user, creating them if they're new.
+    pub async fn see_user(&self, id: Uuid, name: String, provider: String, now: u64) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO users (id, name, provider, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?4)
+                 ON CONFLICT (id) DO UPDATE SET name = ?2, provider = ?3, last_seen = ?4",
+                params![id.to_string(), name, provider, now],
+            )
+            .map(drop)
+        })
+        .await
+    }
+
+    pub async fn user(&self, id: Uuid) -> anyhow::Result<Option<User>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT id, name, provider, first_seen, last_seen FROM users WHERE id = ?1",
+                [id.to_string()],
+                |row| {
+                    Ok(User {
+                        id: uuid(row, 0)?,
+                        name: row.get(1)?,
+                        provider: row.get(2)?,
+                        first_seen: row.get(3)?,
+                        last_seen: row.get(4)?,
+                    })
+                },
+            )
+            .optional()
+        })
+        .await
+    }
+
+    pub async fn avatar(&self, owner: Uuid) -> anyhow::Result<Option<AvatarMeta>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT owner, hash, size, uploaded, name, authors, version, scripts FROM avatars WHERE owner = ?1",
+                [owner.to_string()],
+                |row| avatar(row, 0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// Records the owner's current avatar, or forgets it if `None`.
+    pub async fn set_avatar(&self, owner: Uuid, avatar: Option<AvatarMeta>) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            match avatar {
+                Some(avatar) => conn.execute(
+                    "INSERT OR REPLACE INTO avatars (owner, hash, size, uploaded, name, authors, version, scripts)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        owner.to_string(),
+                        avatar.hash.to_string(),
+                        avatar.size,
+                        avatar.uploaded,
+                        avatar.info.name,
+                        avatar.info.authors,
+                        avatar.info.version,
+                        serde_json::to_string(&avatar.info.scripts).expect("couldn't serialize scripts"),
+                    ],
+                ),
+                None => conn.execute("DELETE FROM avatars WHERE owner = ?1", [owner.to_string()]),
+            }
+            .map(drop)
+        })
+        .await
+    }
+
+    /// Records the avatar as the owner's latest upload, forgetting all but the `keep` latest ones.
+    pub async fn push_version(&self, avatar: AvatarMeta, keep: usize) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            let tx = conn.transaction()?;
+            let owner = avatar.owner.to_string();
+            tx.execute(
+                "INSERT INTO avatar_history (owner, hash, size, uploaded, name, authors, version, scripts)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    owner,
+                    avatar.hash.to_string(),
+                    avatar.size,
+                    avatar.uploaded,
+                    avatar.info.name,
+                    avatar.info.authors,
+                    avatar.info.version,
+                    serde_json::to_string(&avatar.info.scripts).expect("couldn't serialize scripts"),
+                ],
+            )?;
+            tx.execute(
+                "DELETE FROM avatar_history WHERE owner = ?1 AND id NOT IN
+                 (SELECT id FROM avatar_history WHERE owner = ?1 ORDER BY id DESC LIMIT ?2)",
+                params![owner, keep],
+            )?;
+            tx.commit()
+        })
+        .await
+    }
+
+    /// Lists the owner's latest uploads, newest first.
+    pub async fn versions(&self, owner: Uuid) -> anyhow::Result<Vec<AvatarVersion>> {
+        self.run(move |conn| {
+            conn.prepare(
+                "SELECT id, owner, hash, size, uploaded, name, authors, version, scripts FROM avatar_history
+                 WHERE owner = ?1 ORDER BY id DESC",
+            )?
+            .query_map([owner.to_string()], |row| {
+                Ok(AvatarVersion {
+                    id: row.get(0)?,
+                    avatar: avatar(row, 1)?,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    pub async fn version(&self, owner: Uuid, id: u64) -> anyhow::Result<Option<AvatarMeta>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT owner, hash, size, uploaded, name, authors, version, scripts FROM avatar_history
+                 WHERE owner = ?1 AND id = ?2",
+                params![owner.to_string(), id],
+                |row| avatar(row, 0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// Forgets one of the owner's past uploads, returning `false` if there's no such version.
+    pub async fn forget_version(&self, owner: Uuid, id: u64) -> anyhow::Result<bool> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM avatar_history WHERE owner = ?1 AND id = ?2", params![
+                owner.to_string(),
+                id
+            ])
+            .map(|n| n > 0)
+        })
+        .await
+    }
+
+    /// Counts the avatar slots the owner takes up, i.e. the distinct blobs their current avatar and past uploads are
+    /// stored in.
+    pub async fn avatar_count(&self, owner: Uuid) -> anyhow::Result<u64> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM
+                 (SELECT hash FROM avatars WHERE owner = ?1 UNION SELECT hash FROM avatar_history WHERE owner = ?1)",
+                [owner.to_string()],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    /// Counts the avatar slots the owner would take up once they uploaded the blob and their history was trimmed to
+    /// the `keep` latest uploads. Their current avatar is replaced, so it only counts if it's still in their history.
+    pub async fn avatar_count_after(&self, owner: Uuid, hash: AvatarHash, keep: usize) -> anyhow::Result<u64> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM
+                 (SELECT hash FROM (SELECT hash FROM avatar_history WHERE owner = ?1 ORDER BY id DESC LIMIT ?3)
+                  UNION SELECT ?2)",
+                params![owner.to_string(), hash.to_string(), keep.saturating_sub(1)],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    /// Returns whether any avatar, current or past, is stored in the blob.
+    pub async fn has_blob(&self, hash: AvatarHash) -> anyhow::Result<bool> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM avatars WHERE hash = ?1)
+                     OR EXISTS (SELECT 1 FROM avatar_history WHERE hash = ?1)",
+                [hash.to_string()],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    /// Lists the distinct blobs avatars, current or past, are stored in.
+    pub async fn referenced_blobs(&self) -> anyhow::Result<Vec<AvatarHash>> {
+        self.run(|conn| {
+            conn.prepare("SELECT hash FROM avatars UNION SELECT hash FROM avatar_history")?
+                .query_map([], |row| hash(row, 0))?
+                .collect()
+        })
+        .await
+    }
+
+    /// Sums the size in bytes of every distinct blob.
+    pub async fn storage_used(&self) -> anyhow::Result<u64> {
+        self.run(|conn| {
+            conn.query_row(
+                "SELECT COALESCE(SUM(size), 0) FROM
+                 (SELECT hash, size FROM avatars UNION SELECT hash, size FROM avatar_history)",
+                [],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    pub async fn quota(&self, user_id: Uuid) -> anyhow::Result<Option<QuotaOverride>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT max_avatar_size, max_avatars FROM quotas WHERE user_id = ?1",
+                [user_id.to_string()],
+                |row| {
+                    Ok(QuotaOverride {
+                        max_avatar_size: row.get(0)?,
+                        max_avatars: row.get(1)?,
+                    })
+                },
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// Overrides the user's limits, or resets them to the configured ones if `None`. Returns `false` if there was
+    /// nothing to reset.
+    pub async fn set_quota(&self, user_id: Uuid, quota: Option<QuotaOverride>) -> anyhow::Result<bool> {
+        self.run(move |conn| {
+            match quota {
+                Some(quota) => conn.execute(
+                    "INSERT OR REPLACE INTO quotas (user_id, max_avatar_size, max_avatars) VALUES (?1, ?2, ?3)",
+                    params![user_id.to_string(), quota.max_avatar_size, quota.max_avatars],
+                ),
+                None => conn.execute("DELETE FROM quotas WHERE user_id = ?1", [user_id.to_string()]),
+            }
+            .map(|n| n > 0)
+        })
+        .await
+    }
+
+    pub async fn bans(&self) -> anyhow::Result<Vec<Ban>> {
+        self.run(|conn| {
+            conn.prepare("SELECT id, reason, expires FROM bans")?
+                .query_map([], |row| {
+                    Ok(Ban {
+                        id: uuid(row, 0)?,
+                        reason: row.get(1)?,
+                        expires: row.get(2)?,
+                    })
+                })?
+                .collect()
+        })
+        .await
+    }
+
+    pub async fn put_ban(&self, ban: Ban) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO bans (id, reason, expires) VALUES (?1, ?2, ?3)",
+                params![ban.id.to_string(), ban.reason, ban.expires],
+            )
+            .map(drop)
+        })
+        .await
+    }
+
+    /// Removes the user's ban, returning `false` if they weren't banned.
+    pub async fn remove_ban(&self, id: Uuid) -> anyhow::Result<bool> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM bans WHERE id = ?1", [id.to_string()])
+                .map(|n| n > 0)
+        })
+        .await
+    }
+
+    pub async fn badges(&self, user_id: Uuid) -> anyhow::Result<Vec<String>> {
+        self.run(move |conn| {
+            conn.prepare("SELECT badge FROM badges WHERE user_id = ?1 ORDER BY granted")?
+                .query_map([user_id.to_string()], |row| row.get(0))?
+                .collect()
+        })
+        .await
+    }
+
+    /// Grants the badge to the user, returning `false` if they already had it.
+    pub async fn grant_badge(&self, user_id: Uuid, badge: String, now: u64) -> anyhow::Result<bool> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO badges (user_id, badge, granted) VALUES (?1, ?2, ?3)",
+                params![user_id.to_string(), badge, now],
+            )
+            .map(|n| n > 0)
+        })
+        .await
+    }
+
+    /// Revokes the badge from the user, returning `false` if they didn't have it.
+    pub async fn revoke_badge(&self, user_id: Uuid, badge: String) -> anyhow::Result<bool> {
+        self.run(move |conn| {
+            conn.execute("DELETE FROM badges WHERE user_id = ?1 AND badge = ?2", params![
+                user_id.to_string(),
+                badge
+            ])
+            .map(|n| n > 0)
+        })
+        .await
+    }
+
+    pub async fn setting(&self, user_id: Uuid, key: String) -> anyhow::Result<Option<String>> {
+        self.run(move |conn| {
+            conn.query_row(
+                "SELECT value FROM settings WHERE user_id = ?1 AND key = ?2",
+                params![user_id.to_string(), key],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    /// Sets the user's setting, or resets it to its default if `None`.
+    pub async fn set_setting(&self, user_id: Uuid, key: String, value: Option<String>) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            match value {
+                Some(value) => conn.execute(
+                    "INSERT OR REPLACE INTO settings (user_id, key, value) VALUES (?1, ?2, ?3)",
+                    params![user_id.to_string(), key, value],
+                ),
+                None => conn.execute("DELETE FROM settings WHERE user_id = ?1 AND key = ?2", params![
+                    user_id.to_string(),
+                    key
+                ]),
+            }
+            .map(drop)
+        })
+        .await
+    }
+}
+
+fn uuid(row: &Row, index: usize) -> rusqlite::Result<Uuid> {
+    row.get::<_, String>(index)?
+        .parse()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+fn hash(row: &Row, index: usize) -> rusqlite::Result<AvatarHash> {
+    row.get::<_, String>(index)?
+        .parse()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+/// Reads the columns `owner, hash, size, uploaded, name, authors, version, scripts` starting at the index.
+fn avatar(row: &Row, index: usize) -> rusqlite::Result<AvatarMeta> {
+    Ok(AvatarMeta {
+        owner: uuid(row, index)?,
+        hash: hash(row, index + 1)?,
+        size: row.get(index + 2)?,
+        uploaded: row.get(index + 3)?,
+        info: AvatarInfo {
+            name: row.get(index + 4)?,
+            authors: row.get(index + 5)?,
+            version: row.get(index + 6)?,
+            scripts: json(row, index + 7)?,
+        },
+    })
+}
+
+fn json<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    serde_json::from_str(&row.get::<_, String>(index)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::rt::System;
+
+    use super::*;
+
+    #[test]
+    fn migrates_once() -> anyhow::Result<()> {
+        let db = DatabaseService::open_in_memory()?;
+        let conn = Arc::into_inner(db.conn).expect("connection still shared").into_inner();
+
+        assert_eq!(
+            conn.pragma_query_value(None, "user_version", |row| row.get::<_, usize>(0))?,
+            MIGRATIONS.len()
+        );
+
+        // Migrating an up-to-date database is a no-op.
+        DatabaseService::migrate(conn)?;
+        Ok(())
+    }
+
+    #[test]
+    fn stores_metadata() -> anyhow::Result<()> {
+        System::new().block_on(async {
+            let db = DatabaseService::open_in_memory()?;
+            let id = Uuid::from_u128(1);
+
+            db.see_user(id, "Steve".to_string(), "mojang".to_string(), 10).await?;
+            db.see_user(id, "Alex".to_string(), "mojang".to_string(), 20).await?;
+            assert_eq!(
+                db.user(id).await?,
+                Some(User {
+                    id,
+                    name: "Alex".to_string(),
+                    provider: "mojang".to_string(),
+                    first_seen: 10,
+                    last_seen: 20,
+                })
+            );
+
+            let avatar = AvatarMeta {
+                owner: id,
+                hash: AvatarHash::of(b"avatar"),
+                size: 6,
+                uploaded: 30,
+                info: AvatarInfo {
+                    name: "Avatar".to_string(),
+                    scripts: [("script".to_string(), 4)].into(),
+                    ..AvatarInfo::default()
+                },
+            };
+            db.set_avatar(id, Some(avatar.clone())).await?;
+            assert_eq!(db.avatar(id).await?, Some(avatar));
+            db.set_avatar(id, None).await?;
+            assert_eq!(db.avatar(id).await?, None);
+
+            let ban = Ban {
+                id,
+                reason: "griefing".to_string(),
+                expires: Some(40),
+            };
+            db.put_ban(ban.clone()).await?;
+            assert_eq!(db.bans().await?, [ban]);
+            assert!(db.remove_ban(id).await?);
+            assert!(!db.remove_ban(id).await?);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn keeps_latest_versions() -> anyhow::Result<()> {
+        System::new().block_on(async {
+            let db = DatabaseService::open_in_memory()?;
+            let id = Uuid::from_u128(1);
+
+            let hashes = [&b"a"[..], b"b", b"c"].map(AvatarHash::of);
+            for (uploaded, hash) in hashes.into_iter().enumerate() {
+                let avatar = AvatarMeta {
+                    owner: id,
+                    hash,
+                    size: 1,
+                    uploaded: uploaded as u64,
+                    info: AvatarInfo::default(),
+                };
+                db.push_version(avatar, 2).await?;
+            }
+
+            let versions = db.versions(id).await?;
+            assert_eq!(versions.iter().map(|version| version.avatar.hash).collect::<Vec<_>>(), [
+                hashes[2], hashes[1]
+            ]);
+            assert_eq!(db.version(id, versions[1].id).await?, Some(versions[1].avatar.clone()));
+            assert_eq!(db.version(Uuid::from_u128(2), versions[1].id).await?, None);
+
+            // Past uploads keep their blobs alive.
+            assert!(db.has_blob(hashes[1]).await?);
+            assert!(!db.has_blob(hashes[0]).await?);
+            assert_eq!(db.storage_used().await?, 2);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn counts_avatar_slots() -> anyhow::Result<()> {
+        System::new().block_on(async {
+            let db = DatabaseService::open_in_memory()?;
+            let id = Uuid::from_u128(1);
+            let avatar = |hash: &[u8]| AvatarMeta {
+                owner: id,
+                hash: AvatarHash::of(hash),
+                size: 1,
+                uploaded: 0,
+                info: AvatarInfo::default(),
+            };
+
+            assert_eq!(db.avatar_count(id).await?, 0);
+            db.set_avatar(id, Some(avatar(b"a"))).await?;
+            assert_eq!(db.avatar_count(id).await?, 1);
+
+            // Uploading the same avatar again takes up no other slot.
+            for hash in [b"a", b"b", b"a"] {
+                db.push_version(avatar(hash), 3).await?;
+            }
+            assert_eq!(db.avatar_count(id).await?, 2);
+            assert_eq!(db.avatar_count(Uuid::from_u128(2)).await?, 0);
+
+            // Another upload would push the first `a` out of the history, which `a` still takes up a slot in.
+            assert_eq!(db.avatar_count_after(id, AvatarHash::of(b"a"), 3).await?, 2);
+            assert_eq!(db.avatar_count_after(id, AvatarHash::of(b"c"), 3).await?, 3);
+            assert_eq!(db.avatar_count_after(id, AvatarHash::of(b"c"), 2).await?, 2);
+            assert_eq!(db.avatar_count_after(id, AvatarHash::of(b"c"), 0).await?, 1);
+
+            let versions = db.versions(id).await?;
+            assert!(db.forget_version(id, versions[1].id).await?);
+            assert!(!db.forget_version(id, versions[1].id).await?);
+            assert!(!db.forget_version(Uuid::from_u128(2), versions[0].id).await?);
+            assert_eq!(db.avatar_count(id).await?, 1);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn stores_badges_and_settings() -> anyhow::Result<()> {
+        System::new().block_on(async {
+            let db = DatabaseService::open_in_memory()?;
+            let (id, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+
+            assert!(db.grant_badge(id, "dev".to_string(), 40).await?);
+            assert!(!db.grant_badge(id, "dev".to_string(), 50).await?);
+            assert!(db.grant_badge(id, "donor".to_string(), 30).await?);
+            assert_eq!(db.badges(id).await?, ["donor", "dev"]);
+            assert!(db.badges(other).await?.is_empty());
+            assert!(db.revoke_badge(id, "donor".to_string()).await?);
+            assert!(!db.revoke_badge(id, "donor".to_string()).await?);
+            assert_eq!(db.badges(id).await?, ["dev"]);
+
+            db.set_setting(id, "color".to_string(), Some("red".to_string())).await?;
+            db.set_setting(id, "color".to_string(), Some("blue".to_string())).await?;
+            assert_eq!(db.setting(id, "color".to_string()).await?.as_deref(), Some("blue"));
+            assert_eq!(db.setting(other, "color".to_string()).await?, None);
+            db.set_setting(id, "color".to_string(), None).await?;
+            assert_eq!(db.setting(id, "color".to_string()).await?, None);
+
+            Ok(())
+        })
+    }
+}
@@ -1,5 +1,10 @@
+pub mod allow;
 pub mod auth;
+pub mod avatar;
+pub mod ban;
+pub mod database;
 pub mod http;
+pub mod socket;
 
 use std::any::{
     type_name,
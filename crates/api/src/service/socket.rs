@@ -0,0 +1,50 @@
+use std::{
+    num::NonZeroUsize,
+    time::Duration,
+};
+
+use crate::service::Service;
+
+#[derive(Clone)]
+pub struct SocketConfig {
+    /// How many sockets a single user may have open at once before the oldest ones get evicted.
+    pub max_connections: NonZeroUsize,
+    /// How often the server pings each socket.
+    pub heartbeat_interval: Duration,
+    /// How long a socket may stay silent, including not answering pings, before it's closed.
+    pub idle_timeout: Duration,
+    /// How long a socket may stay connected without sending a valid access token.
+    pub auth_timeout: Duration,
+    /// Largest message in bytes accepted from clients, after inflating compressed ones.
+    pub max_message_size: usize,
+    /// Whether text frames carrying the JSON representation of the protocol are accepted.
+    pub json_debug: bool,
+    /// Bytes queued for a socket past which droppable messages to it are dropped instead of delivered.
+    pub outbound_high_water: usize,
+    /// Bytes queued for a socket past which it's disconnected.
+    pub outbound_limit: usize,
+    /// How many users a single socket may subscribe to; further subscriptions are rejected.
+    pub max_subscriptions: usize,
+    /// Whether `permessage-deflate` is negotiated with clients offering it.
+    pub compression: bool,
+    /// Size in bytes below which outgoing messages aren't worth compressing.
+    pub compression_threshold: usize,
+}
+
+pub struct SocketService {
+    config: SocketConfig,
+}
+
+impl Service for SocketService {}
+
+impl SocketService {
+    #[inline]
+    pub fn new(config: SocketConfig) -> Self {
+        Self { config }
+    }
+
+    #[inline]
+    pub fn config(&self) -> &SocketConfig {
+        &self.config
+    }
+}
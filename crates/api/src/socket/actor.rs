@@ -1,13 +1,23 @@
-use std::sync::Arc;
+use std::{
+    mem,
+    sync::Arc,
+    time::Instant,
+};
 
 use actix::{
     Actor,
+    ActorContext,
+    Addr,
+    AsyncContext,
+    Handler,
+    Message as ActixMessage,
     StreamHandler,
 };
+use actix_http::ws::Codec as WsCodec;
 use actix_web::{
     web,
     HttpRequest,
-    Responder,
+    HttpResponse,
 };
 use actix_web_actors::{
     ws,
@@ -18,84 +28,451 @@ use actix_web_actors::{
         WebsocketContext,
     },
 };
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
 use uuid::Uuid;
 
 use crate::{
-    service::auth::AuthService,
-    socket::message::{
-        MsgError,
-        WsCode,
-        C2S,
-        S2C,
+    cluster::{
+        self,
+        Event,
+    },
+    metrics,
+    service::{
+        auth::AuthService,
+        ban::BanService,
+        socket::SocketService,
     },
+    socket::{
+        deflate::{
+            self,
+            Deflater,
+            Inflater,
+        },
+        hub,
+        message::{
+            Codec,
+            MsgError,
+            SubError,
+            WsCode,
+            C2S,
+            S2C,
+        },
+        outbound::{
+            frame_len,
+            Admission,
+            Outbound,
+            Queue,
+        },
+        state::{
+            Action,
+            State,
+        },
+    },
+    FxHashMap,
+    FxHashSet,
 };
 
+type Sockets = FxHashMap<Uuid, Vec<(Uuid, Addr<Socket>)>>;
+
+/// Authenticated sockets of each user along with their access tokens, shared across workers.
+pub(crate) static SOCKETS: Lazy<RwLock<Sockets>> = Lazy::new(Default::default);
+
+/// Size of the close frame the WebSocket codec writes for the reason.
+#[inline]
+fn close_frame_len(reason: &CloseReason) -> usize {
+    frame_len(2 + reason.description.as_ref().map_or(0, String::len))
+}
+
 pub struct Socket {
-    authorized: bool,
+    state: State,
+    /// Whether the client speaks the JSON debug protocol, in which case replies are sent as text frames.
+    json: bool,
+    /// Whether the client sent a data frame yet, telling which protocol it speaks.
+    protocol_known: bool,
+    /// Whether [`S2C::Auth`] is held back until the protocol is known, for sockets authenticated during the upgrade.
+    ack_pending: bool,
+    last_seen: Instant,
+    /// The user this socket is registered as in [`SOCKETS`], kept after the state moves on so it can deregister.
+    registered: Option<Uuid>,
+    subscriptions: FxHashSet<Uuid>,
+    queue: Arc<Queue>,
+    /// Present if `permessage-deflate` was negotiated, in which case data frames bypass the context.
+    deflater: Option<Deflater>,
+    dropped: u64,
     auth: Arc<AuthService>,
+    bans: BanService,
+    service: Arc<SocketService>,
 }
 
+/// Closes the socket with the given code and reason.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct Kick(pub WsCode, pub String);
+
+/// Tells the socket its subscription to the target was dropped by the server.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct Dropped(pub Uuid, pub SubError);
+
+/// Sends the message to the socket's client.
+#[derive(ActixMessage)]
+#[rtype(result = "()")]
+pub struct Deliver(pub S2C);
+
 impl Socket {
-    pub fn start(auth: Arc<AuthService>, req: &HttpRequest, stream: web::Payload) -> impl Responder {
-        ws::start(Self { authorized: false, auth }, req, stream)
+    pub fn start(
+        auth: Arc<AuthService>,
+        bans: BanService,
+        service: Arc<SocketService>,
+        state: State,
+        req: &HttpRequest,
+        stream: web::Payload,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let config = service.config();
+        let compress = config.compression && deflate::negotiate(req);
+        let deflater = compress.then(|| Deflater::new(config.compression_threshold));
+        let max_size = config.max_message_size;
+
+        let queue = Arc::new(Queue::default());
+        let socket = Self {
+            state,
+            json: false,
+            protocol_known: false,
+            ack_pending: false,
+            last_seen: Instant::now(),
+            registered: None,
+            subscriptions: FxHashSet::default(),
+            queue: queue.clone(),
+            deflater,
+            dropped: 0,
+            auth,
+            bans,
+            service,
+        };
+
+        let mut res = ws::handshake(req)?;
+        if compress {
+            res.insert_header((deflate::SEC_WEBSOCKET_EXTENSIONS, deflate::RESPONSE));
+        }
+
+        let stream = Inflater::new(stream, compress, max_size);
+        let context = WebsocketContext::with_codec(socket, stream, WsCodec::new().max_size(max_size));
+        Ok(res.streaming(Outbound::new(Box::pin(context), queue)))
+    }
+
+    /// Closes every open socket authenticated as the user.
+    pub fn kick(user_id: Uuid, code: WsCode, reason: &str) {
+        let Some(sockets) = ({ SOCKETS.write().remove(&user_id) }) else {
+            return
+        };
+
+        for (.., socket) in sockets {
+            socket.do_send(Kick(code, reason.to_string()));
+        }
+    }
+
+    /// Closes every open socket authenticated as the user with the given access token.
+    pub fn kick_token(user_id: Uuid, access_token: Uuid, code: WsCode, reason: &str) {
+        let kicked = {
+            let mut sockets = SOCKETS.write();
+            let Some(addrs) = sockets.get_mut(&user_id) else { return };
+
+            let (kicked, kept) = addrs.drain(..).partition(|&(token, ..)| token == access_token);
+            *addrs = kept;
+
+            if addrs.is_empty() {
+                sockets.remove(&user_id);
+            }
+
+            kicked
+        };
+
+        for (.., socket) in kicked {
+            socket.do_send(Kick(code, reason.to_string()));
+        }
+    }
+
+    fn send(&mut self, ctx: &mut <Self as Actor>::Context, msg: S2C) {
+        if self.state == State::Closing {
+            return
+        }
+
+        let config = self.service.config();
+        match self
+            .queue
+            .admit(msg.is_droppable(), config.outbound_high_water, config.outbound_limit)
+        {
+            Admission::Queue => {}
+            Admission::Drop => {
+                metrics::FRAMES_DROPPED.add(1);
+                self.dropped += 1;
+                return
+            }
+            Admission::Disconnect => {
+                // The client isn't reading; don't bother flushing what's left.
+                metrics::SOCKETS_THROTTLED.add(1);
+                self.queue.abort();
+                return self.close(ctx, WsCode::TryAgainLater, "outbound queue full".to_string())
+            }
+        }
+
+        if let Some(ref mut deflater) = self.deflater {
+            let frame = if self.json {
+                deflater.frame(deflate::OP_TEXT, msg.to_json().as_bytes())
+            } else {
+                deflater.frame(deflate::OP_BINARY, &msg.to_bytes())
+            };
+
+            self.queue.push_raw(frame);
+        } else if self.json {
+            let text = msg.to_json();
+            self.queue.push(frame_len(text.len()));
+            ctx.text(text);
+        } else {
+            let bytes = msg.to_bytes();
+            self.queue.push(frame_len(bytes.len()));
+            ctx.binary(bytes);
+        }
+    }
+
+    /// Settles the protocol on the client's first data frame, sending the held back [`S2C::Auth`] in it.
+    fn detect_protocol(&mut self, ctx: &mut <Self as Actor>::Context, json: bool) {
+        if self.protocol_known {
+            return
+        }
+
+        self.json = json;
+        self.protocol_known = true;
+        if mem::take(&mut self.ack_pending) {
+            self.send(ctx, S2C::Auth);
+        }
+    }
+
+    fn close(&mut self, ctx: &mut <Self as Actor>::Context, code: WsCode, reason: String) {
+        self.state = State::Closing;
+
+        let reason = CloseReason {
+            code: code.into(),
+            description: Some(reason),
+        };
+        self.queue.push(close_frame_len(&reason));
+        ctx.close(Some(reason));
+        ctx.stop();
+    }
+
+    fn authenticate(&mut self, ctx: &mut <Self as Actor>::Context, token: Uuid) {
+        let Some(user_id) = self.auth.check_access_token(token) else {
+            return self.close(ctx, WsCode::Unauthorized, "invalid access token".to_string())
+        };
+
+        if let Some(ban) = self.bans.check(user_id) {
+            return self.close(ctx, WsCode::Banned, format!("banned: {}", ban.reason))
+        }
+
+        self.state = State::Authenticated { user_id, token };
+        self.register(ctx);
+    }
+
+    fn receive(&mut self, ctx: &mut <Self as Actor>::Context, msg: Result<C2S, MsgError>) {
+        match msg {
+            Ok(msg) => match self.state.next(msg) {
+                Action::Authenticate(token) => self.authenticate(ctx, token),
+                Action::Handle(msg) => self.handle_message(ctx, msg),
+                Action::Close(code, reason) => self.close(ctx, code, reason),
+                Action::Ignore => {}
+            },
+            Err(..) if self.state == State::Closing => {}
+            Err(e) => {
+                let code = match e {
+                    MsgError::BadEnum(..) => WsCode::UnsupportedData,
+                    MsgError::BadLength(..) | MsgError::BadJson(..) | MsgError::BadUtf8(..) => {
+                        WsCode::InvalidFramePayloadData
+                    }
+                };
+
+                self.close(ctx, code, format!("{e}"))
+            }
+        }
+    }
+
+    fn handle_message(&mut self, ctx: &mut <Self as Actor>::Context, msg: C2S) {
+        let State::Authenticated { user_id, .. } = self.state else {
+            return
+        };
+
+        match msg {
+            C2S::Token(..) => {}
+            C2S::Ping(id, sync, data) => {
+                cluster::broadcast(Event::Ping {
+                    owner: user_id,
+                    id,
+                    data: data.clone(),
+                });
+
+                let msg = S2C::Ping(user_id.as_u128(), id, data);
+                if sync {
+                    self.send(ctx, msg.clone());
+                }
+
+                hub::publish(user_id, msg);
+            }
+            C2S::Sub(target) => {
+                let target = Uuid::from_u128(target);
+                if target == user_id {
+                    self.send(ctx, SubError::Yourself.toast(target));
+                } else if self.subscriptions.len() >= self.service.config().max_subscriptions {
+                    self.send(ctx, SubError::TooMany.toast(target));
+                } else if self.subscriptions.insert(target) {
+                    hub::subscribe(target, user_id, ctx.address());
+
+                    // Bans are stored before their target's subscribers are dropped, so checking only once
+                    // subscribed can't miss a ban that lands in between.
+                    if self.bans.check(target).is_some() {
+                        self.subscriptions.remove(&target);
+                        hub::unsubscribe(target, &ctx.address());
+                        self.send(ctx, SubError::Banned.toast(target));
+                    }
+                }
+            }
+            C2S::UnSub(target) => {
+                let target = Uuid::from_u128(target);
+                if self.subscriptions.remove(&target) {
+                    hub::unsubscribe(target, &ctx.address());
+                }
+            }
+        }
+    }
+
+    fn register(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let State::Authenticated { user_id, token } = self.state else {
+            return
+        };
+
+        let evicted = {
+            let mut sockets = SOCKETS.write();
+
+            // Revocation removes the token before kicking its sockets, so checking it again while registered
+            // closes the gap where it was revoked after being checked but before this socket could be kicked.
+            if self.auth.check_access_token(token).is_none() {
+                drop(sockets);
+                return self.close(ctx, WsCode::Unauthorized, "access token revoked".to_string())
+            }
+
+            let addrs = sockets.entry(user_id).or_default();
+            addrs.push((token, ctx.address()));
+
+            let excess = addrs.len().saturating_sub(self.service.config().max_connections.get());
+            addrs.drain(..excess).collect::<Vec<_>>()
+        };
+
+        self.registered = Some(user_id);
+        if self.protocol_known || !self.service.config().json_debug {
+            self.send(ctx, S2C::Auth);
+        } else {
+            self.ack_pending = true;
+        }
+
+        for (.., socket) in evicted {
+            socket.do_send(Kick(WsCode::TooManyConnections, "too many connections".to_string()));
+        }
     }
 }
 
 impl Actor for Socket {
     type Context = WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // Sockets may already be authenticated during the upgrade request.
+        self.register(ctx);
+
+        ctx.run_interval(self.service.config().heartbeat_interval, |this, ctx| {
+            if this.last_seen.elapsed() >= this.service.config().idle_timeout {
+                this.close(ctx, WsCode::GoingAway, "idle timeout".to_string());
+            } else {
+                this.queue.push(frame_len(0));
+                ctx.ping(b"");
+            }
+        });
+
+        ctx.run_later(self.service.config().auth_timeout, |this, ctx| {
+            if this.state == State::AwaitingToken {
+                this.close(ctx, WsCode::Unauthorized, "authentication timeout".to_string());
+            }
+        });
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        let addr = ctx.address();
+        for target in mem::take(&mut self.subscriptions) {
+            hub::unsubscribe(target, &addr);
+        }
+
+        let Some(user_id) = self.registered else { return };
+        if self.dropped > 0 {
+            log::debug!("Socket of {user_id} dropped {} frames.", self.dropped);
+        }
+
+        let mut sockets = SOCKETS.write();
+        if let Some(addrs) = sockets.get_mut(&user_id) {
+            addrs.retain(|(.., other)| *other != addr);
+            if addrs.is_empty() {
+                sockets.remove(&user_id);
+            }
+        }
+    }
+}
+
+impl Handler<Kick> for Socket {
+    type Result = ();
+
+    fn handle(&mut self, Kick(code, reason): Kick, ctx: &mut Self::Context) {
+        self.close(ctx, code, reason);
+    }
+}
+
+impl Handler<Dropped> for Socket {
+    type Result = ();
+
+    fn handle(&mut self, Dropped(target, reason): Dropped, ctx: &mut Self::Context) {
+        if self.subscriptions.remove(&target) {
+            self.send(ctx, reason.toast(target));
+        }
+    }
+}
+
+impl Handler<Deliver> for Socket {
+    type Result = ();
+
+    fn handle(&mut self, Deliver(msg): Deliver, ctx: &mut Self::Context) {
+        self.send(ctx, msg);
+    }
 }
 
 impl StreamHandler<Result<Message, ProtocolError>> for Socket {
     fn handle(&mut self, item: Result<Message, ProtocolError>, ctx: &mut Self::Context) {
+        self.last_seen = Instant::now();
         match item {
-            Ok(Message::Binary(msg)) => match C2S::try_from(msg) {
-                Ok(C2S::Token(token)) => {
-                    let Ok(repr) = token.utf8_chunks().try_fold(String::new(), |mut repr, chunk| {
-                        anyhow::ensure!(chunk.invalid().len() == 0);
-
-                        repr.push_str(chunk.valid());
-                        Ok(repr)
-                    }) else {
-                        ctx.close(Some(CloseReason {
-                            code: WsCode::InvalidFramePayloadData.into(),
-                            description: Some("invalid UTF-8 access token string".to_string()),
-                        }));
-                        return
-                    };
-
-                    let token = match Uuid::try_parse(&repr) {
-                        Ok(token) => token,
-                        Err(e) => {
-                            ctx.close(Some(CloseReason {
-                                code: WsCode::InvalidFramePayloadData.into(),
-                                description: Some(format!("broken access token string: {e}")),
-                            }));
-                            return
-                        }
-                    };
-
-                    if self.auth.check_access_token(token) {
-                        self.authorized = true;
-                        ctx.binary(S2C::Auth);
-                    } else {
-                        ctx.close(Some(CloseReason {
-                            code: WsCode::Unauthorized.into(),
-                            description: Some("invalid access token".to_string()),
-                        }));
-                    }
-                }
-                Ok(..) => {}
-                Err(e) => ctx.close(Some(CloseReason {
-                    code: match e {
-                        MsgError::BadEnum(..) => WsCode::UnsupportedData,
-                        MsgError::BadLength(..) => WsCode::InvalidFramePayloadData,
-                    }
-                    .into(),
-                    description: Some(format!("{e}")),
-                })),
-            },
+            Ok(Message::Binary(msg)) => {
+                self.detect_protocol(ctx, false);
+                self.receive(ctx, C2S::try_from(msg))
+            }
+            Ok(Message::Text(msg)) if self.service.config().json_debug => {
+                self.detect_protocol(ctx, true);
+                self.receive(ctx, C2S::from_json(&msg))
+            }
+            Ok(Message::Ping(msg)) => {
+                self.queue.push(frame_len(msg.len()));
+                ctx.pong(&msg);
+            }
+            Ok(Message::Close(reason)) => {
+                self.state = State::Closing;
+                self.queue.push(reason.as_ref().map_or(frame_len(0), close_frame_len));
+                ctx.close(reason);
+                ctx.stop();
+            }
             Ok(..) => {}
-            Err(..) => {}
+            Err(e) => self.close(ctx, WsCode::ProtocolError, format!("{e}")),
         }
     }
 }
@@ -0,0 +1,443 @@
+//! `permessage-deflate` (RFC 7692) support, negotiated without context takeover in either direction so every message
+//! is compressed independently. `actix-http` rejects frames with reserved bits set, so compressed client frames are
+//! inflated before they reach its parser, and compressed server frames are written around it.
+
+use std::{
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use actix_web::{
+    error::PayloadError,
+    http::header::{
+        HeaderName,
+        HeaderValue,
+    },
+    web::{
+        self,
+        BufMut,
+    },
+    HttpRequest,
+};
+use flate2::{
+    Compress,
+    Compression,
+    Decompress,
+    FlushCompress,
+    FlushDecompress,
+    Status,
+};
+use futures_core::Stream;
+
+use crate::metrics;
+
+pub const SEC_WEBSOCKET_EXTENSIONS: HeaderName = HeaderName::from_static("sec-websocket-extensions");
+pub const RESPONSE: HeaderValue =
+    HeaderValue::from_static("permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+
+/// Trailer stripped from compressed messages and appended back before inflating them.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+
+/// Whether the client offered `permessage-deflate` with parameters this server can honor.
+pub fn negotiate(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all(SEC_WEBSOCKET_EXTENSIONS)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            params.next() == Some("permessage-deflate")
+                && params.all(|param| {
+                    let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                    match name.trim() {
+                        "client_max_window_bits" | "server_no_context_takeover" | "client_no_context_takeover" => true,
+                        // Only the full window is supported when compressing.
+                        "server_max_window_bits" => value.trim().trim_matches('"') == "15",
+                        _ => false,
+                    }
+                })
+        })
+}
+
+fn write_frame(dst: &mut web::BytesMut, first: u8, payload: &[u8], mask: bool) {
+    dst.put_u8(first);
+
+    let mask_bit = if mask { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => dst.put_u8(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            dst.put_u8(mask_bit | 126);
+            dst.put_u16(len as u16);
+        }
+        len => {
+            dst.put_u8(mask_bit | 127);
+            dst.put_u64(len as u64);
+        }
+    }
+
+    if mask {
+        // A zero mask key leaves the payload as is.
+        dst.put_u32(0);
+    }
+
+    dst.put_slice(payload);
+}
+
+/// Writes outgoing data frames, compressing those at least as large as the threshold.
+pub struct Deflater {
+    compress: Compress,
+    threshold: usize,
+}
+
+impl Deflater {
+    #[inline]
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            threshold,
+        }
+    }
+
+    pub fn frame(&mut self, opcode: u8, payload: &[u8]) -> web::Bytes {
+        let mut frame = web::BytesMut::new();
+        match self.deflate(payload) {
+            Some(compressed) => {
+                metrics::DEFLATE_BYTES_SAVED.add((payload.len() - compressed.len()) as u64);
+                write_frame(&mut frame, FIN | RSV1 | opcode, &compressed, false);
+            }
+            None => write_frame(&mut frame, FIN | opcode, payload, false),
+        }
+
+        frame.freeze()
+    }
+
+    /// Compresses the payload, or returns `None` if it's too small to bother or doesn't shrink.
+    fn deflate(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < self.threshold {
+            return None
+        }
+
+        self.compress.reset();
+
+        let mut out = Vec::with_capacity(payload.len() + 16);
+        loop {
+            let consumed = self.compress.total_in() as usize;
+            self.compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)
+                .ok()?;
+
+            if self.compress.total_in() as usize == payload.len() && out.len() < out.capacity() {
+                break
+            }
+
+            out.reserve(payload.len().max(64));
+        }
+
+        out.truncate(out.len().saturating_sub(TRAILER.len()));
+        (out.len() < payload.len()).then_some(out)
+    }
+}
+
+struct Compressed {
+    opcode: u8,
+    data: Vec<u8>,
+}
+
+/// Rewrites the client's frames so compressed messages reach the WebSocket parser inflated.
+pub struct Inflater<S> {
+    inner: S,
+    enabled: bool,
+    max_size: usize,
+    buf: web::BytesMut,
+    message: Option<Compressed>,
+    decompress: Decompress,
+    done: bool,
+}
+
+impl<S> Inflater<S> {
+    #[inline]
+    pub fn new(inner: S, enabled: bool, max_size: usize) -> Self {
+        Self {
+            inner,
+            enabled,
+            max_size,
+            buf: web::BytesMut::new(),
+            message: None,
+            decompress: Decompress::new(false),
+            done: false,
+        }
+    }
+
+    fn inflate(&mut self, data: &[u8]) -> Result<Vec<u8>, PayloadError> {
+        self.decompress.reset(false);
+
+        let mut out = Vec::with_capacity(data.len() * 2);
+        for input in [data, &TRAILER] {
+            let start = self.decompress.total_in() as usize;
+            loop {
+                let consumed = self.decompress.total_in() as usize - start;
+                let status = self
+                    .decompress
+                    .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                    .map_err(|_| PayloadError::EncodingCorrupted)?;
+
+                if out.len() > self.max_size {
+                    return Err(PayloadError::Overflow)
+                }
+
+                let consumed = self.decompress.total_in() as usize - start;
+                if status == Status::StreamEnd || (consumed == input.len() && out.len() < out.capacity()) {
+                    break
+                }
+
+                out.reserve(input.len().max(1024));
+            }
+        }
+
+        metrics::INFLATE_BYTES_SAVED.add(out.len().saturating_sub(data.len()) as u64);
+        Ok(out)
+    }
+
+    /// Rewrites the next complete frame in the buffer, if any.
+    fn next_frame(&mut self) -> Result<Option<web::Bytes>, PayloadError> {
+        loop {
+            let buf = &self.buf[..];
+            if buf.len() < 2 {
+                return Ok(None)
+            }
+
+            let (first, second) = (buf[0], buf[1]);
+            let masked = second & 0x80 != 0;
+            let (len, mut header) = match second & 0x7f {
+                126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+                127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()) as usize, 10),
+                126 | 127 => return Ok(None),
+                len => (len as usize, 2),
+            };
+
+            if len > self.max_size {
+                return Err(PayloadError::Overflow)
+            }
+
+            let key = if masked {
+                header += 4;
+                buf.get(header - 4..header).map(|key| [key[0], key[1], key[2], key[3]])
+            } else {
+                Some([0; 4])
+            };
+
+            let Some(key) = key.filter(|_| buf.len() >= header + len) else {
+                return Ok(None)
+            };
+
+            let opcode = first & 0x0f;
+            let is_control = opcode & 0x08 != 0;
+            if !is_control && (first & RSV1 != 0 || (opcode == OP_CONTINUATION && self.message.is_some())) {
+                let frame = self.buf.split_to(header + len);
+                let mut payload = frame[header..].to_vec();
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= key[i % 4];
+                }
+
+                let message = self.message.get_or_insert(Compressed {
+                    opcode,
+                    data: Vec::new(),
+                });
+                message.data.extend_from_slice(&payload);
+                if message.data.len() > self.max_size {
+                    return Err(PayloadError::Overflow)
+                }
+
+                if first & FIN == 0 {
+                    continue
+                }
+
+                let Compressed { opcode, data } = self.message.take().unwrap();
+                let inflated = self.inflate(&data)?;
+
+                let mut frame = web::BytesMut::new();
+                write_frame(&mut frame, FIN | opcode, &inflated, true);
+                return Ok(Some(frame.freeze()))
+            }
+
+            // Uncompressed frames pass through untouched.
+            return Ok(Some(self.buf.split_to(header + len).freeze()))
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<web::Bytes, PayloadError>> + Unpin> Stream for Inflater<S> {
+    type Item = Result<web::Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if !self.enabled {
+            return Pin::new(&mut self.inner).poll_next(cx)
+        }
+
+        loop {
+            match self.next_frame() {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Ok(None) => {}
+                Err(e) => {
+                    // The offending frame is still buffered, so end the stream rather than fail on it again.
+                    self.buf.clear();
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e)))
+                }
+            }
+
+            if self.done {
+                return Poll::Ready(None)
+            }
+
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(bytes))) => self.buf.extend_from_slice(&bytes),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => self.done = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{
+        stream,
+        StreamExt,
+    };
+
+    use super::*;
+
+    const OP_PING: u8 = 0x9;
+
+    fn client_frame(first: u8, payload: &[u8]) -> web::Bytes {
+        let key = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = web::BytesMut::new();
+        write_frame(&mut frame, first, payload, false);
+
+        // Rewrite the header as masked and mask the payload in place.
+        let header = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        let mut masked = frame.split_to(header);
+        masked.put_slice(&key);
+        masked.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ key[i % 4]));
+        masked.freeze()
+    }
+
+    fn inflated_frame(opcode: u8, payload: &[u8]) -> web::Bytes {
+        let mut frame = web::BytesMut::new();
+        write_frame(&mut frame, FIN | opcode, payload, true);
+        frame.freeze()
+    }
+
+    fn compress(payload: &[u8]) -> Vec<u8> {
+        Deflater::new(0).deflate(payload).expect("payload didn't shrink")
+    }
+
+    /// Feeds the bytes to an inflater a few at a time, so frames straddle reads.
+    async fn inflate(bytes: &[u8], max_size: usize) -> Vec<Result<web::Bytes, PayloadError>> {
+        let chunks = bytes
+            .chunks(3)
+            .map(|chunk| Ok(web::Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        Inflater::new(stream::iter(chunks), true, max_size).collect().await
+    }
+
+    #[test]
+    fn compresses_from_threshold() {
+        let mut deflater = Deflater::new(64);
+
+        let small = deflater.frame(OP_BINARY, &[0; 63]);
+        assert_eq!(small[0], FIN | OP_BINARY);
+        assert_eq!(&small[2..], &[0; 63]);
+
+        let large = deflater.frame(OP_BINARY, &[0; 64]);
+        assert_eq!(large[0], FIN | RSV1 | OP_BINARY);
+        assert!(large.len() < 64);
+
+        // Payloads that don't shrink go out uncompressed.
+        let mut state = 0x2545_f491_u32;
+        let noise = (0..256)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        let frame = deflater.frame(OP_BINARY, &noise);
+        assert_eq!(frame[0], FIN | OP_BINARY);
+        assert_eq!(&frame[4..], &noise[..]);
+    }
+
+    #[actix_web::test]
+    async fn round_trips_compressed_frames() {
+        let payload = b"hello hello hello hello hello hello".repeat(8);
+        let frame = client_frame(FIN | RSV1 | OP_BINARY, &compress(&payload));
+
+        let frames = inflate(&frame, 64 * 1024).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref().unwrap(), &inflated_frame(OP_BINARY, &payload));
+    }
+
+    #[actix_web::test]
+    async fn reassembles_fragmented_frames_around_control_frames() {
+        let payload = b"fragmented fragmented fragmented".repeat(8);
+        let compressed = compress(&payload);
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+
+        let ping = client_frame(FIN | OP_PING, b"ping");
+        let mut bytes = client_frame(RSV1 | OP_TEXT, head).to_vec();
+        bytes.extend_from_slice(&ping);
+        bytes.extend_from_slice(&client_frame(FIN | OP_CONTINUATION, tail));
+
+        let frames = inflate(&bytes, 64 * 1024).await;
+        assert_eq!(frames.len(), 2);
+        // Control frames may interleave with fragments and pass through as is.
+        assert_eq!(frames[0].as_ref().unwrap(), &ping);
+        assert_eq!(frames[1].as_ref().unwrap(), &inflated_frame(OP_TEXT, &payload));
+    }
+
+    #[actix_web::test]
+    async fn passes_uncompressed_frames_through() {
+        let whole = client_frame(FIN | OP_BINARY, b"plain");
+        let first = client_frame(OP_TEXT, b"pla");
+        let last = client_frame(FIN | OP_CONTINUATION, b"in");
+
+        let bytes = [&whole[..], &first, &last].concat();
+        let frames = inflate(&bytes, 64 * 1024).await;
+        let frames = frames.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(frames, [whole, first, last]);
+    }
+
+    #[actix_web::test]
+    async fn rejects_oversized_messages() {
+        let frame = client_frame(FIN | RSV1 | OP_BINARY, &compress(&[0; 1024]));
+        let frames = inflate(&frame, 1023).await;
+        assert!(matches!(frames[..], [Err(PayloadError::Overflow)]));
+
+        let frame = client_frame(FIN | OP_BINARY, &[0; 1024]);
+        let frames = inflate(&frame, 1023).await;
+        assert!(matches!(frames[..], [Err(PayloadError::Overflow)]));
+    }
+
+    #[actix_web::test]
+    async fn leaves_frames_alone_when_disabled() {
+        let frame = client_frame(FIN | RSV1 | OP_BINARY, &compress(&[0; 1024]));
+        let frames = Inflater::new(stream::iter([Ok(frame.clone())]), false, 64 * 1024)
+            .collect::<Vec<Result<_, PayloadError>>>()
+            .await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref().unwrap(), &frame);
+    }
+}
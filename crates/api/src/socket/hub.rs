@@ -0,0 +1,182 @@
+use std::thread;
+
+use actix::Addr;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use uuid::Uuid;
+
+use crate::{
+    socket::{
+        actor::{
+            Deliver,
+            Dropped,
+            Socket,
+        },
+        message::{
+            SubError,
+            S2C,
+        },
+    },
+    FxHashMap,
+};
+
+type Subscribers<T> = FxHashMap<Uuid, Vec<(Uuid, T)>>;
+
+/// Routes messages from users to the sinks subscribed to them. Subscriptions are split into shards by the subscribed
+/// user, so publishers from any worker only contend with others publishing to or subscribing to the same shard.
+pub struct Router<T> {
+    shards: Box<[RwLock<Subscribers<T>>]>,
+}
+
+impl<T: PartialEq> Router<T> {
+    /// Creates a router with at least the given amount of shards, rounded up to a power of two.
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1).next_power_of_two()).map(|_| RwLock::default()).collect(),
+        }
+    }
+
+    #[inline]
+    fn shard(&self, target: Uuid) -> &RwLock<Subscribers<T>> {
+        // FxHash only carries input bits upwards, so its low bits miss most of the UUID; pick by the top bits instead.
+        let bits = self.shards.len().trailing_zeros();
+        let hash = fxhash::hash64(&target).checked_shr(64 - bits).unwrap_or(0);
+        &self.shards[hash as usize]
+    }
+
+    pub fn subscribe(&self, target: Uuid, user_id: Uuid, sink: T) {
+        self.shard(target).write().entry(target).or_default().push((user_id, sink));
+    }
+
+    pub fn unsubscribe(&self, target: Uuid, sink: &T) {
+        let mut subscribers = self.shard(target).write();
+        if let Some(sinks) = subscribers.get_mut(&target) {
+            sinks.retain(|(.., other)| other != sink);
+            if sinks.is_empty() {
+                subscribers.remove(&target);
+            }
+        }
+    }
+
+    /// Removes every subscription to the target, returning the sinks that were subscribed.
+    pub fn remove(&self, target: Uuid) -> Vec<T> {
+        self.shard(target)
+            .write()
+            .remove(&target)
+            .map(|sinks| sinks.into_iter().map(|(.., sink)| sink).collect())
+            .unwrap_or_default()
+    }
+
+    /// Calls `deliver` with every sink subscribed to the owner. The owner's shard is read-locked in the meantime, so
+    /// `deliver` must not block.
+    pub fn publish(&self, owner: Uuid, mut deliver: impl FnMut(&T)) {
+        if let Some(sinks) = self.shard(owner).read().get(&owner) {
+            for (.., sink) in sinks {
+                deliver(sink);
+            }
+        }
+    }
+
+    /// Returns the users subscribed to the target, once for each of their subscribed sinks.
+    pub fn subscribers(&self, target: Uuid) -> Vec<Uuid> {
+        self.shard(target)
+            .read()
+            .get(&target)
+            .map(|sinks| sinks.iter().map(|&(user_id, ..)| user_id).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Sockets subscribed to each user's avatar along with the users they're authenticated as, shared across workers.
+static SUBSCRIBERS: Lazy<Router<Addr<Socket>>> = Lazy::new(|| {
+    let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+    Router::new(cores * 4)
+});
+
+pub fn subscribe(target: Uuid, user_id: Uuid, socket: Addr<Socket>) {
+    SUBSCRIBERS.subscribe(target, user_id, socket);
+}
+
+pub fn unsubscribe(target: Uuid, socket: &Addr<Socket>) {
+    SUBSCRIBERS.unsubscribe(target, socket);
+}
+
+/// Drops every subscription to the target, telling the subscribed sockets why.
+pub fn drop_subscribers(target: Uuid, reason: SubError) {
+    for socket in SUBSCRIBERS.remove(target) {
+        socket.do_send(Dropped(target, reason));
+    }
+}
+
+/// Delivers the message to every socket subscribed to the owner.
+pub fn publish(owner: Uuid, msg: S2C) {
+    SUBSCRIBERS.publish(owner, |socket| socket.do_send(Deliver(msg.clone())));
+}
+
+/// Returns the users subscribed to the target, once for each of their subscribed sockets.
+pub fn subscribers(target: Uuid) -> Vec<Uuid> {
+    SUBSCRIBERS.subscribers(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: u128) -> impl Iterator<Item = Uuid> {
+        (0..n).map(Uuid::from_u128)
+    }
+
+    #[test]
+    fn rounds_shards_up() {
+        assert_eq!(Router::<u32>::new(0).shards.len(), 1);
+        assert_eq!(Router::<u32>::new(5).shards.len(), 8);
+        assert_eq!(Router::<u32>::new(16).shards.len(), 16);
+    }
+
+    #[test]
+    fn shards_by_target() {
+        let router = Router::<u32>::new(8);
+        for target in ids(64) {
+            assert!(std::ptr::eq(router.shard(target), router.shard(target)));
+        }
+
+        let used = ids(64)
+            .map(|target| router.shard(target) as *const _)
+            .collect::<crate::FxHashSet<_>>();
+        assert!(used.len() > 1, "every target landed in the same shard");
+
+        // Targets sharing a shard still keep separate subscribers.
+        let (a, b) = ids(64)
+            .flat_map(|a| ids(64).map(move |b| (a, b)))
+            .find(|&(a, b)| a != b && std::ptr::eq(router.shard(a), router.shard(b)))
+            .unwrap();
+        router.subscribe(a, Uuid::nil(), 1);
+        router.subscribe(b, Uuid::nil(), 2);
+        assert_eq!(router.remove(a), [1]);
+        assert_eq!(router.remove(b), [2]);
+    }
+
+    #[test]
+    fn fans_out_to_subscribers() {
+        let router = Router::new(4);
+        let (owner, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        for (i, user_id) in ids(3).enumerate() {
+            router.subscribe(owner, user_id, i);
+        }
+        router.subscribe(other, Uuid::nil(), 9);
+
+        let mut delivered = Vec::new();
+        router.publish(owner, |&sink| delivered.push(sink));
+        assert_eq!(delivered, [0, 1, 2]);
+        assert_eq!(router.subscribers(owner), ids(3).collect::<Vec<_>>());
+
+        router.unsubscribe(owner, &1);
+        delivered.clear();
+        router.publish(owner, |&sink| delivered.push(sink));
+        assert_eq!(delivered, [0, 2]);
+
+        assert_eq!(router.remove(owner), [0, 2]);
+        router.publish(owner, |_| panic!("delivered after every subscriber was removed"));
+        assert_eq!(router.subscribers(other), [Uuid::nil()]);
+    }
+}
@@ -0,0 +1,148 @@
+//! JSON representation of the socket protocol carried by text frames, for debugging with generic WebSocket clients.
+
+use actix_web::web;
+use base64::{
+    engine::general_purpose::STANDARD,
+    Engine,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use uuid::Uuid;
+
+use crate::socket::message::{
+    MsgError,
+    ToastKind,
+    C2S,
+    S2C,
+};
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JsonC2S {
+    Token { token: String },
+    Ping { id: u32, sync: bool, data: String },
+    Sub { id: Uuid },
+    UnSub { id: Uuid },
+}
+
+impl TryFrom<JsonC2S> for C2S {
+    type Error = MsgError;
+
+    fn try_from(value: JsonC2S) -> Result<Self, Self::Error> {
+        Ok(match value {
+            JsonC2S::Token { token } => C2S::Token(web::Bytes::from(token)),
+            JsonC2S::Ping { id, sync, data } => C2S::Ping(
+                id,
+                sync,
+                web::Bytes::from(STANDARD.decode(data).map_err(|e| MsgError::BadJson(e.to_string()))?),
+            ),
+            JsonC2S::Sub { id } => C2S::Sub(id.as_u128()),
+            JsonC2S::UnSub { id } => C2S::UnSub(id.as_u128()),
+        })
+    }
+}
+
+impl C2S {
+    pub fn from_json(text: &str) -> Result<Self, MsgError> {
+        serde_json::from_str::<JsonC2S>(text)
+            .map_err(|e| MsgError::BadJson(e.to_string()))?
+            .try_into()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JsonS2C {
+    Auth,
+    Ping {
+        owner: Uuid,
+        id: u32,
+        data: String,
+    },
+    Event {
+        owner: Uuid,
+    },
+    Toast {
+        kind: ToastKind,
+        title: String,
+        message: String,
+    },
+}
+
+impl From<&S2C> for JsonS2C {
+    fn from(value: &S2C) -> Self {
+        match value {
+            S2C::Auth => Self::Auth,
+            &S2C::Ping(owner, id, ref data) => Self::Ping {
+                owner: Uuid::from_u128(owner),
+                id,
+                data: STANDARD.encode(data),
+            },
+            &S2C::Event(owner) => Self::Event {
+                owner: Uuid::from_u128(owner),
+            },
+            S2C::Toast(kind, title, message) => Self::Toast {
+                kind: *kind,
+                title: title.clone(),
+                message: message.clone(),
+            },
+        }
+    }
+}
+
+impl S2C {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&JsonS2C::from(self)).expect("couldn't serialize message")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_c2s() {
+        let id = Uuid::from_u128(1);
+        assert_eq!(
+            C2S::from_json(r#"{"type":"token","token":"abc"}"#).unwrap(),
+            C2S::Token(web::Bytes::from_static(b"abc"))
+        );
+        assert_eq!(
+            C2S::from_json(r#"{"type":"ping","id":7,"sync":true,"data":"aGk="}"#).unwrap(),
+            C2S::Ping(7, true, web::Bytes::from_static(b"hi"))
+        );
+        assert_eq!(
+            C2S::from_json(&format!(r#"{{"type":"sub","id":"{id}"}}"#)).unwrap(),
+            C2S::Sub(1)
+        );
+        assert_eq!(
+            C2S::from_json(&format!(r#"{{"type":"unsub","id":"{id}"}}"#)).unwrap(),
+            C2S::UnSub(1)
+        );
+
+        for bad in [
+            "not json",
+            r#"{"type":"nope"}"#,
+            r#"{"type":"ping","id":7,"sync":true,"data":"!"}"#,
+        ] {
+            assert!(matches!(C2S::from_json(bad), Err(MsgError::BadJson(..))), "{bad}");
+        }
+    }
+
+    #[test]
+    fn encodes_s2c() {
+        let owner = Uuid::from_u128(1);
+        assert_eq!(S2C::Auth.to_json(), r#"{"type":"auth"}"#);
+        assert_eq!(
+            S2C::Ping(1, 7, web::Bytes::from_static(b"hi")).to_json(),
+            format!(r#"{{"type":"ping","owner":"{owner}","id":7,"data":"aGk="}}"#)
+        );
+        assert_eq!(S2C::Event(1).to_json(), format!(r#"{{"type":"event","owner":"{owner}"}}"#));
+        assert_eq!(
+            S2C::Toast(ToastKind::Warning, "Title".to_string(), "message".to_string()).to_json(),
+            r#"{"type":"toast","kind":"warning","title":"Title","message":"message"}"#
+        );
+    }
+}
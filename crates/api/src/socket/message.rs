@@ -1,11 +1,16 @@
 use std::ops::RangeInclusive;
 
-use actix_web::web;
+use actix_web::web::{
+    self,
+    BufMut,
+};
 use actix_web_actors::ws::CloseCode;
+use serde::Serialize;
 use thiserror::Error;
+use uuid::Uuid;
 
 #[repr(u16)]
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Debug)]
 pub enum WsCode {
     NormalClosure = 1000,
     GoingAway,
@@ -60,8 +65,27 @@ pub enum MsgError {
     BadEnum(&'static str, RangeInclusive<usize>, usize),
     #[error("invalid buffer size for {}: must be {} {} bytes, got {}", .0, if *.2 { "exactly" } else { "at least" }, .1, .3)]
     BadLength(&'static str, usize, bool, usize),
+    #[error("invalid JSON message: {0}")]
+    BadJson(String),
+    #[error("invalid UTF-8 in {0}")]
+    BadUtf8(&'static str),
 }
 
+/// Binary wire format of socket messages, shared by both directions so clients can be written against the same types.
+pub trait Codec: Sized {
+    fn encode(&self, buf: &mut web::BytesMut);
+
+    fn decode(buf: web::Bytes) -> Result<Self, MsgError>;
+
+    #[inline]
+    fn to_bytes(&self) -> web::Bytes {
+        let mut buf = web::BytesMut::new();
+        self.encode(&mut buf);
+        buf.freeze()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub enum C2S {
     Token(web::Bytes),
     Ping(u32, bool, web::Bytes),
@@ -69,11 +93,32 @@ pub enum C2S {
     UnSub(u128),
 }
 
-impl TryFrom<web::Bytes> for C2S {
-    type Error = MsgError;
+impl Codec for C2S {
+    fn encode(&self, buf: &mut web::BytesMut) {
+        match self {
+            C2S::Token(token) => {
+                buf.put_u8(0);
+                buf.put_slice(token);
+            }
+            &C2S::Ping(id, sync, ref data) => {
+                buf.put_u8(1);
+                buf.put_u32(id);
+                buf.put_u8(sync.into());
+                buf.put_slice(data);
+            }
+            &C2S::Sub(id) => {
+                buf.put_u8(2);
+                buf.put_u128(id);
+            }
+            &C2S::UnSub(id) => {
+                buf.put_u8(3);
+                buf.put_u128(id);
+            }
+        }
+    }
 
-    fn try_from(mut buf: web::Bytes) -> Result<Self, Self::Error> {
-        if buf.len() == 0 {
+    fn decode(mut buf: web::Bytes) -> Result<Self, MsgError> {
+        if buf.is_empty() {
             Err(MsgError::BadLength("C2S", 1, false, 0))
         } else {
             match buf[0] {
@@ -109,14 +154,204 @@ impl TryFrom<web::Bytes> for C2S {
     }
 }
 
+impl TryFrom<web::Bytes> for C2S {
+    type Error = MsgError;
+
+    #[inline]
+    fn try_from(buf: web::Bytes) -> Result<Self, Self::Error> {
+        Self::decode(buf)
+    }
+}
+
+/// Why the server refused a subscription, or ended one it had accepted.
+#[derive(Error, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SubError {
+    #[error("you can't subscribe to yourself")]
+    Yourself,
+    #[error("you're subscribed to too many users")]
+    TooMany,
+    #[error("they're banned")]
+    Banned,
+}
+
+impl SubError {
+    /// Tells the subscriber through a toast, as clients have no message of their own for it.
+    pub fn toast(self, target: Uuid) -> S2C {
+        S2C::Toast(ToastKind::Warning, format!("Not subscribed to {target}"), self.to_string())
+    }
+}
+
+/// Kind of toast, which decides how clients style it.
+#[repr(u8)]
+#[derive(Serialize, Copy, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ToastKind {
+    Default,
+    Warning,
+    Error,
+    Cheese,
+}
+
+impl TryFrom<u8> for ToastKind {
+    type Error = MsgError;
+
+    fn try_from(value: u8) -> Result<Self, MsgError> {
+        match value {
+            0 => Ok(ToastKind::Default),
+            1 => Ok(ToastKind::Warning),
+            2 => Ok(ToastKind::Error),
+            3 => Ok(ToastKind::Cheese),
+            other => Err(MsgError::BadEnum("ToastKind", 0..=3, other.into())),
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
 pub enum S2C {
     Auth,
+    /// A ping relayed from the owner's avatar to a subscriber.
+    Ping(u128, u32, web::Bytes),
+    /// The owner's avatar changed, so subscribers should fetch it again.
+    Event(u128),
+    /// A notification popping up in the client, with a title and a message that mustn't contain NUL characters.
+    Toast(ToastKind, String, String),
+}
+
+impl S2C {
+    /// Whether the message may be dropped instead of delivered to a slow consumer.
+    #[inline]
+    pub fn is_droppable(&self) -> bool {
+        matches!(self, S2C::Ping(..))
+    }
 }
 
-impl Into<web::Bytes> for S2C {
-    fn into(self) -> web::Bytes {
+impl Codec for S2C {
+    fn encode(&self, buf: &mut web::BytesMut) {
         match self {
-            S2C::Auth => web::Bytes::from_static(&[0]),
+            S2C::Auth => buf.put_u8(0),
+            &S2C::Ping(owner, id, ref data) => {
+                buf.put_u8(1);
+                buf.put_u128(owner);
+                buf.put_u32(id);
+                buf.put_slice(data);
+            }
+            &S2C::Event(owner) => {
+                buf.put_u8(2);
+                buf.put_u128(owner);
+            }
+            S2C::Toast(kind, title, message) => {
+                buf.put_u8(3);
+                buf.put_u8(*kind as u8);
+                buf.put_slice(title.as_bytes());
+                buf.put_u8(0);
+                buf.put_slice(message.as_bytes());
+            }
+        }
+    }
+
+    fn decode(mut buf: web::Bytes) -> Result<Self, MsgError> {
+        if buf.is_empty() {
+            Err(MsgError::BadLength("S2C", 1, false, 0))
+        } else {
+            match buf[0] {
+                0 => {
+                    if buf.len() == 1 {
+                        Ok(S2C::Auth)
+                    } else {
+                        Err(MsgError::BadLength("S2C::Auth", 1, true, buf.len()))
+                    }
+                }
+                1 => {
+                    if buf.len() >= 21 {
+                        Ok(S2C::Ping(
+                            u128::from_be_bytes((&buf[1..17]).try_into().unwrap()),
+                            u32::from_be_bytes((&buf[17..21]).try_into().unwrap()),
+                            buf.split_off(21),
+                        ))
+                    } else {
+                        Err(MsgError::BadLength("S2C::Ping", 21, false, buf.len()))
+                    }
+                }
+                2 => {
+                    if buf.len() == 17 {
+                        Ok(S2C::Event(u128::from_be_bytes((&buf[1..]).try_into().unwrap())))
+                    } else {
+                        Err(MsgError::BadLength("S2C::Event", 17, true, buf.len()))
+                    }
+                }
+                3 => {
+                    if buf.len() >= 2 {
+                        let text = std::str::from_utf8(&buf[2..]).map_err(|_| MsgError::BadUtf8("S2C::Toast"))?;
+                        let (title, message) = text.split_once('\0').unwrap_or((text, ""));
+                        Ok(S2C::Toast(buf[1].try_into()?, title.to_string(), message.to_string()))
+                    } else {
+                        Err(MsgError::BadLength("S2C::Toast", 2, false, buf.len()))
+                    }
+                }
+                other => Err(MsgError::BadEnum("S2C", 0..=3, other.into())),
+            }
+        }
+    }
+}
+
+impl From<S2C> for web::Bytes {
+    #[inline]
+    fn from(value: S2C) -> Self {
+        value.to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn bytes() -> impl Strategy<Value = web::Bytes> {
+        prop::collection::vec(any::<u8>(), 0..256).prop_map(web::Bytes::from)
+    }
+
+    fn c2s() -> impl Strategy<Value = C2S> {
+        prop_oneof![
+            bytes().prop_map(C2S::Token),
+            (any::<u32>(), any::<bool>(), bytes()).prop_map(|(id, sync, data)| C2S::Ping(id, sync, data)),
+            any::<u128>().prop_map(C2S::Sub),
+            any::<u128>().prop_map(C2S::UnSub),
+        ]
+    }
+
+    fn s2c() -> impl Strategy<Value = S2C> {
+        prop_oneof![
+            Just(S2C::Auth),
+            (any::<u128>(), any::<u32>(), bytes()).prop_map(|(owner, id, data)| S2C::Ping(owner, id, data)),
+            any::<u128>().prop_map(S2C::Event),
+            (0..=3u8, "[^\0]*", "[^\0]*").prop_map(|(kind, title, message)| S2C::Toast(
+                kind.try_into().unwrap(),
+                title,
+                message
+            )),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn c2s_round_trip(msg in c2s()) {
+            prop_assert_eq!(C2S::decode(msg.to_bytes())?, msg);
+        }
+
+        #[test]
+        fn s2c_round_trip(msg in s2c()) {
+            prop_assert_eq!(S2C::decode(msg.to_bytes())?, msg);
+        }
+
+        #[test]
+        fn c2s_decode_never_panics(buf in bytes()) {
+            let _ = C2S::decode(buf);
+        }
+
+        #[test]
+        fn s2c_decode_never_panics(buf in bytes()) {
+            let _ = S2C::decode(buf);
         }
     }
 }
@@ -1,2 +1,7 @@
 pub mod actor;
+pub mod deflate;
+pub mod hub;
+pub mod json;
 pub mod message;
+pub mod outbound;
+pub mod state;
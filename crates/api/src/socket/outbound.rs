@@ -0,0 +1,213 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    task::{
+        Context,
+        Poll,
+        Waker,
+    },
+};
+
+use actix_web::web;
+use futures_core::Stream;
+use parking_lot::Mutex;
+
+/// Size of a frame the server writes with a payload of the given length, as the WebSocket codec encodes it.
+#[inline]
+pub fn frame_len(payload: usize) -> usize {
+    payload
+        + match payload {
+            0..=125 => 2,
+            126..=0xffff => 4,
+            _ => 10,
+        }
+}
+
+/// What to do with a message about to be queued.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Queue,
+    /// The queue is past its high-water mark and the message may be dropped.
+    Drop,
+    /// The queue is past its hard limit; the client isn't reading.
+    Disconnect,
+}
+
+/// Bookkeeping of a socket's encoded frames that have been queued but not yet written to the connection.
+#[derive(Default)]
+pub struct Queue {
+    queued: AtomicUsize,
+    aborted: AtomicBool,
+    /// Frames encoded outside of the socket's context, written ahead of the context's own.
+    raw: Mutex<(VecDeque<web::Bytes>, Option<Waker>)>,
+}
+
+impl Queue {
+    /// Amount of bytes waiting to be written, counting every frame in full.
+    #[inline]
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Accounts for a frame of `len` bytes written through the socket's context, including its header.
+    #[inline]
+    pub fn push(&self, len: usize) {
+        self.queued.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Decides whether a message may still be queued.
+    pub fn admit(&self, droppable: bool, high_water: usize, limit: usize) -> Admission {
+        let queued = self.queued();
+        if queued >= limit {
+            Admission::Disconnect
+        } else if queued >= high_water && droppable {
+            Admission::Drop
+        } else {
+            Admission::Queue
+        }
+    }
+
+    /// Queues an already encoded frame, bypassing the socket's context.
+    pub fn push_raw(&self, frame: web::Bytes) {
+        self.push(frame.len());
+
+        let mut raw = self.raw.lock();
+        raw.0.push_back(frame);
+        if let Some(waker) = raw.1.take() {
+            waker.wake();
+        }
+    }
+
+    /// Ends the response stream without flushing the remaining frames, dropping the connection.
+    #[inline]
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Response body of a socket that keeps track of how much of its [`Queue`] has been written.
+pub struct Outbound<S> {
+    inner: S,
+    queue: Arc<Queue>,
+}
+
+impl<S> Outbound<S> {
+    #[inline]
+    pub fn new(inner: S, queue: Arc<Queue>) -> Self {
+        Self { inner, queue }
+    }
+}
+
+impl<E, S: Stream<Item = Result<web::Bytes, E>> + Unpin> Stream for Outbound<S> {
+    type Item = Result<web::Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.queue.aborted.load(Ordering::Relaxed) {
+            return Poll::Ready(None)
+        }
+
+        let raw = {
+            let mut raw = self.queue.raw.lock();
+            let frame = raw.0.pop_front();
+            if frame.is_none() {
+                raw.1 = Some(cx.waker().clone());
+            }
+
+            frame
+        };
+
+        let poll = match raw {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None => Pin::new(&mut self.inner).poll_next(cx),
+        };
+
+        if let Poll::Ready(Some(Ok(ref bytes))) = poll {
+            // Every frame written through the context is pushed along with its header, so this only saturates if a
+            // frame is written without being pushed, which would be a bug.
+            let len = bytes.len();
+            let _ = self
+                .queue
+                .queued
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                    Some(queued.saturating_sub(len))
+                });
+        }
+
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{
+        stream,
+        StreamExt,
+    };
+
+    use super::*;
+
+    type Frames = stream::Iter<std::vec::IntoIter<Result<web::Bytes, ()>>>;
+
+    fn outbound(frames: &[&'static [u8]]) -> (Outbound<Frames>, Arc<Queue>) {
+        let queue = Arc::new(Queue::default());
+        for frame in frames {
+            queue.push(frame.len());
+        }
+
+        let frames = frames
+            .iter()
+            .map(|&frame| Ok(web::Bytes::from_static(frame)))
+            .collect::<Vec<_>>();
+        (Outbound::new(stream::iter(frames), queue.clone()), queue)
+    }
+
+    #[test]
+    fn counts_frame_headers() {
+        assert_eq!(frame_len(0), 2);
+        assert_eq!(frame_len(125), 127);
+        assert_eq!(frame_len(126), 130);
+        assert_eq!(frame_len(0x10000), 0x10000 + 10);
+    }
+
+    #[test]
+    fn admits_until_limit() {
+        let queue = Queue::default();
+        assert_eq!(queue.admit(true, 4, 8), Admission::Queue);
+
+        queue.push(4);
+        assert_eq!(queue.admit(false, 4, 8), Admission::Queue);
+        assert_eq!(queue.admit(true, 4, 8), Admission::Drop);
+
+        queue.push(4);
+        assert_eq!(queue.admit(false, 4, 8), Admission::Disconnect);
+    }
+
+    #[actix_web::test]
+    async fn drains_what_was_pushed() {
+        let (mut outbound, queue) = outbound(&[b"\x82\x01a", b"\x82\x02bc"]);
+        queue.push_raw(web::Bytes::from_static(b"\xc2\x01d"));
+        assert_eq!(queue.queued(), 10);
+
+        // Raw frames go out ahead of the context's own.
+        assert_eq!(outbound.next().await.unwrap().unwrap(), &b"\xc2\x01d"[..]);
+        assert_eq!(queue.queued(), 7);
+
+        while outbound.next().await.is_some() {}
+        assert_eq!(queue.queued(), 0);
+    }
+
+    #[actix_web::test]
+    async fn disconnects_when_aborted() {
+        let (mut outbound, queue) = outbound(&[b"\x82\x01a"]);
+        queue.abort();
+        assert!(outbound.next().await.is_none());
+    }
+}
@@ -0,0 +1,131 @@
+use actix_web::web;
+use uuid::Uuid;
+
+use crate::socket::message::{
+    WsCode,
+    C2S,
+};
+
+/// Lifecycle of a socket connection.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum State {
+    /// Connected, but hasn't sent a valid access token yet.
+    AwaitingToken,
+    /// Authenticated as the user the access token was issued to.
+    Authenticated { user_id: Uuid, token: Uuid },
+    /// A close frame has been sent; any further message is ignored.
+    Closing,
+}
+
+/// What a socket should do with an incoming message given its current [`State`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Validate the access token, transitioning to [`State::Authenticated`] if it's valid.
+    Authenticate(Uuid),
+    /// Process the message as an authenticated user.
+    Handle(C2S),
+    /// Close the socket, transitioning to [`State::Closing`].
+    Close(WsCode, String),
+    /// Drop the message.
+    Ignore,
+}
+
+impl State {
+    pub fn next(&self, msg: C2S) -> Action {
+        match (self, msg) {
+            (Self::Closing, ..) => Action::Ignore,
+            (Self::AwaitingToken, C2S::Token(token)) => match parse_token(&token) {
+                Ok(token) => Action::Authenticate(token),
+                Err(e) => Action::Close(WsCode::InvalidFramePayloadData, e),
+            },
+            (Self::AwaitingToken, ..) => Action::Close(WsCode::Unauthorized, "must authenticate first".to_string()),
+            (Self::Authenticated { .. }, C2S::Token(..)) => {
+                Action::Close(WsCode::ProtocolError, "already authenticated".to_string())
+            }
+            (Self::Authenticated { .. }, msg) => Action::Handle(msg),
+        }
+    }
+}
+
+/// Parses the UTF-8 hyphenated access token string sent by [`C2S::Token`].
+pub fn parse_token(token: &web::Bytes) -> Result<Uuid, String> {
+    let repr = std::str::from_utf8(token).map_err(|_| "invalid UTF-8 access token string".to_string())?;
+    Uuid::try_parse(repr).map_err(|e| format!("broken access token string: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0c8b4bd2-8f9f-4f5e-9f3b-5d1c7a6b2e41";
+
+    fn token() -> Uuid {
+        Uuid::try_parse(TOKEN).unwrap()
+    }
+
+    fn authenticated() -> State {
+        State::Authenticated {
+            user_id: Uuid::nil(),
+            token: token(),
+        }
+    }
+
+    #[test]
+    fn awaiting_token_accepts_token() {
+        assert_eq!(
+            State::AwaitingToken.next(C2S::Token(web::Bytes::from_static(TOKEN.as_bytes()))),
+            Action::Authenticate(token())
+        );
+    }
+
+    #[test]
+    fn awaiting_token_rejects_malformed_token() {
+        for token in [&b"\xff\xfe"[..], b"not-a-uuid"] {
+            assert!(matches!(
+                State::AwaitingToken.next(C2S::Token(web::Bytes::from_static(token))),
+                Action::Close(WsCode::InvalidFramePayloadData, ..)
+            ));
+        }
+    }
+
+    #[test]
+    fn awaiting_token_rejects_other_messages() {
+        for msg in [C2S::Ping(0, false, web::Bytes::new()), C2S::Sub(0), C2S::UnSub(0)] {
+            assert!(matches!(
+                State::AwaitingToken.next(msg),
+                Action::Close(WsCode::Unauthorized, ..)
+            ));
+        }
+    }
+
+    #[test]
+    fn authenticated_rejects_second_token() {
+        assert!(matches!(
+            authenticated().next(C2S::Token(web::Bytes::from_static(TOKEN.as_bytes()))),
+            Action::Close(WsCode::ProtocolError, ..)
+        ));
+    }
+
+    #[test]
+    fn authenticated_handles_other_messages() {
+        for msg in [
+            C2S::Ping(1, true, web::Bytes::from_static(b"data")),
+            C2S::Sub(2),
+            C2S::UnSub(3),
+        ] {
+            assert_eq!(authenticated().next(msg.clone()), Action::Handle(msg));
+        }
+    }
+
+    #[test]
+    fn closing_ignores_everything() {
+        for msg in [
+            C2S::Token(web::Bytes::from_static(TOKEN.as_bytes())),
+            C2S::Ping(0, false, web::Bytes::new()),
+            C2S::Sub(0),
+            C2S::UnSub(0),
+        ] {
+            assert_eq!(State::Closing.next(msg), Action::Ignore);
+        }
+    }
+}
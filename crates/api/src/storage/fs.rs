@@ -0,0 +1,304 @@
+use std::{
+    fs,
+    io::{
+        self,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use actix_web::{
+    rt::{
+        spawn,
+        task::JoinHandle,
+    },
+    web,
+};
+use uuid::Uuid;
+
+use crate::{
+    encode_uuid,
+    random_uuid,
+    storage::{
+        AvatarHash,
+        BlobInfo,
+        Storage,
+    },
+};
+
+/// Stores blobs under `blobs/ab/cd/abcd...` and each user's reference under `refs/{uuid}`, writing both atomically
+/// through a temporary file in `tmp/` so readers never observe partial files.
+#[derive(Clone)]
+pub struct FsStorage {
+    root: Arc<Path>,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        for dir in ["blobs", "refs", "tmp"] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+
+        Ok(Self { root: root.into() })
+    }
+
+    fn blob_path(&self, hash: AvatarHash) -> PathBuf {
+        let hex = hash.to_string();
+        self.root.join("blobs").join(&hex[0..2]).join(&hex[2..4]).join(hex)
+    }
+
+    fn ref_path(&self, user_id: Uuid) -> PathBuf {
+        self.root.join("refs").join(encode_uuid(user_id))
+    }
+
+    /// Writes the file through a temporary one, so it's either fully written or not at all.
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let tmp = self.root.join("tmp").join(encode_uuid(random_uuid()));
+        let result = (|| {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(data)?;
+            file.sync_all()?;
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::rename(&tmp, path)
+        })();
+
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+
+        result
+    }
+
+    /// Runs the blocking operation on the thread pool.
+    fn run<T: 'static + Send>(
+        &self,
+        op: impl 'static + Send + FnOnce(&Self) -> io::Result<T>,
+    ) -> JoinHandle<anyhow::Result<T>> {
+        let this = self.clone();
+        spawn(async move { Ok(web::block(move || op(&this)).await??) })
+    }
+}
+
+/// Maps a missing file to `None`.
+fn optional<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl Storage for FsStorage {
+    fn put(&self, hash: AvatarHash, data: web::Bytes) -> JoinHandle<anyhow::Result<()>> {
+        self.run(move |this| {
+            let path = this.blob_path(hash);
+            match optional(fs::File::options().write(true).open(&path))? {
+                Some(file) => file.set_modified(SystemTime::now()),
+                None => this.write_atomic(&path, &data),
+            }
+        })
+    }
+
+    fn get(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<Option<web::Bytes>>> {
+        self.run(move |this| Ok(optional(fs::read(this.blob_path(hash)))?.map(web::Bytes::from)))
+    }
+
+    fn reference(&self, user_id: Uuid) -> JoinHandle<anyhow::Result<Option<AvatarHash>>> {
+        self.run(move |this| {
+            optional(fs::read_to_string(this.ref_path(user_id)))?
+                .map(|hash| hash.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .transpose()
+        })
+    }
+
+    fn set_reference(&self, user_id: Uuid, hash: Option<AvatarHash>) -> JoinHandle<anyhow::Result<()>> {
+        self.run(move |this| {
+            let path = this.ref_path(user_id);
+            match hash {
+                Some(hash) => this.write_atomic(&path, hash.to_string().as_bytes()),
+                None => optional(fs::remove_file(path)).map(|_| ()),
+            }
+        })
+    }
+
+    fn blobs(&self) -> JoinHandle<anyhow::Result<Vec<BlobInfo>>> {
+        self.run(|this| {
+            let mut blobs = Vec::new();
+            // Skip anything that isn't a shard or a blob, rather than fail or risk treating it as one.
+            for first in fs::read_dir(this.root.join("blobs"))? {
+                let first = first?;
+                if !first.file_type()?.is_dir() {
+                    continue
+                }
+
+                for second in fs::read_dir(first.path())? {
+                    let second = second?;
+                    if !second.file_type()?.is_dir() {
+                        continue
+                    }
+
+                    for blob in fs::read_dir(second.path())? {
+                        let blob = blob?;
+                        let Some(hash) = blob.file_name().to_str().and_then(|name| name.parse().ok()) else {
+                            continue
+                        };
+                        if !blob.file_type()?.is_file() {
+                            continue
+                        }
+
+                        let metadata = blob.metadata()?;
+                        blobs.push(BlobInfo {
+                            hash,
+                            size: metadata.len(),
+                            modified: metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                        });
+                    }
+                }
+            }
+
+            Ok(blobs)
+        })
+    }
+
+    fn references(&self) -> JoinHandle<anyhow::Result<Vec<(Uuid, AvatarHash)>>> {
+        self.run(|this| {
+            let mut refs = Vec::new();
+            for reference in fs::read_dir(this.root.join("refs"))? {
+                let reference = reference?;
+                let Some(user_id) = reference.file_name().to_str().and_then(|name| Uuid::parse_str(name).ok()) else {
+                    continue
+                };
+
+                // The reference may have been removed since listing it.
+                if let Some(hash) = optional(fs::read_to_string(reference.path()))? {
+                    refs.push((
+                        user_id,
+                        hash.trim()
+                            .parse()
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                    ));
+                }
+            }
+
+            Ok(refs)
+        })
+    }
+
+    fn delete(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<bool>> {
+        self.run(move |this| Ok(optional(fs::remove_file(this.blob_path(hash)))?.is_some()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn puts_and_gets_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path()).unwrap();
+
+        let data = web::Bytes::from_static(b"avatar");
+        let hash = AvatarHash::of(&data);
+        assert_eq!(storage.get(hash).await.unwrap().unwrap(), None);
+
+        storage.put(hash, data.clone()).await.unwrap().unwrap();
+        assert_eq!(storage.get(hash).await.unwrap().unwrap(), Some(data.clone()));
+
+        // Storing it again only refreshes its modification time.
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(storage.blob_path(hash))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        storage.put(hash, data.clone()).await.unwrap().unwrap();
+        assert_eq!(storage.get(hash).await.unwrap().unwrap(), Some(data));
+
+        let blobs = storage.blobs().await.unwrap().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].hash, hash);
+        assert_eq!(blobs[0].size, 6);
+        assert!(blobs[0].modified > old.duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        assert_eq!(fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn tracks_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path()).unwrap();
+        let (a, b) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let (first, second) = (AvatarHash::of(b"first"), AvatarHash::of(b"second"));
+
+        assert_eq!(storage.reference(a).await.unwrap().unwrap(), None);
+        storage.set_reference(a, Some(first)).await.unwrap().unwrap();
+        storage.set_reference(b, Some(first)).await.unwrap().unwrap();
+        storage.set_reference(b, Some(second)).await.unwrap().unwrap();
+        assert_eq!(storage.reference(a).await.unwrap().unwrap(), Some(first));
+        assert_eq!(storage.reference(b).await.unwrap().unwrap(), Some(second));
+
+        let mut refs = storage.references().await.unwrap().unwrap();
+        refs.sort();
+        assert_eq!(refs, [(a, first), (b, second)]);
+
+        storage.set_reference(a, None).await.unwrap().unwrap();
+        storage.set_reference(a, None).await.unwrap().unwrap();
+        assert_eq!(storage.reference(a).await.unwrap().unwrap(), None);
+        assert_eq!(storage.references().await.unwrap().unwrap(), [(b, second)]);
+    }
+
+    #[actix_web::test]
+    async fn lists_and_deletes_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FsStorage::new(dir.path()).unwrap();
+
+        let mut hashes = Vec::new();
+        for data in [&b"first"[..], b"second", b"third"] {
+            let hash = AvatarHash::of(data);
+            storage.put(hash, web::Bytes::from_static(data)).await.unwrap().unwrap();
+            hashes.push(hash);
+        }
+
+        // Stray files don't pass for blobs or shards, nor do directories named like blobs.
+        let blobs = dir.path().join("blobs");
+        let shard = storage.blob_path(hashes[0]).parent().unwrap().to_owned();
+        fs::write(shard.join("stray"), b"").unwrap();
+        fs::write(blobs.join("stray"), b"").unwrap();
+        fs::write(shard.parent().unwrap().join("stray"), b"").unwrap();
+        fs::create_dir_all(storage.blob_path(AvatarHash::of(b"fourth"))).unwrap();
+
+        let mut listed = storage
+            .blobs()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|blob| blob.hash)
+            .collect::<Vec<_>>();
+        listed.sort();
+        hashes.sort();
+        assert_eq!(listed, hashes);
+
+        assert!(storage.delete(hashes[0]).await.unwrap().unwrap());
+        assert!(!storage.delete(hashes[0]).await.unwrap().unwrap());
+        assert_eq!(storage.get(hashes[0]).await.unwrap().unwrap(), None);
+        assert_eq!(storage.blobs().await.unwrap().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,314 @@
+//! Sweeps blobs no avatar refers to anymore. An upload stores its blob before referencing it, so it holds the blob's
+//! lock in between, and blobs younger than the grace period are always kept for uploads in other instances.
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::{
+    OwnedRwLockReadGuard,
+    OwnedRwLockWriteGuard,
+    RwLock,
+};
+
+use crate::{
+    service::database::DatabaseService,
+    storage::{
+        AvatarHash,
+        Storage,
+    },
+    unix_now,
+    FxHashMap,
+    FxHashSet,
+};
+
+/// Locks of blobs being uploaded or swept, shared by uploads and exclusive to sweeps.
+static LOCKS: Lazy<Mutex<FxHashMap<AvatarHash, Arc<RwLock<()>>>>> = Lazy::new(Default::default);
+
+/// Holds a blob's lock until dropped.
+pub struct BlobLock<G> {
+    hash: AvatarHash,
+    guard: Option<G>,
+}
+
+impl<G> Drop for BlobLock<G> {
+    fn drop(&mut self) {
+        self.guard.take();
+        release(self.hash);
+    }
+}
+
+fn lock(hash: AvatarHash) -> Arc<RwLock<()>> {
+    LOCKS.lock().entry(hash).or_default().clone()
+}
+
+/// Forgets the blob's lock once nobody holds or waits for it.
+fn release(hash: AvatarHash) {
+    let mut locks = LOCKS.lock();
+    if locks.get(&hash).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+        locks.remove(&hash);
+    }
+}
+
+/// Keeps sweeps from deleting the blob until dropped, waiting for one that's deleting it to finish first.
+pub async fn hold(hash: AvatarHash) -> BlobLock<OwnedRwLockReadGuard<()>> {
+    BlobLock {
+        hash,
+        guard: Some(lock(hash).read_owned().await),
+    }
+}
+
+/// Locks the blob for deletion, or returns `None` if an upload holds it.
+fn try_sweep(hash: AvatarHash) -> Option<BlobLock<OwnedRwLockWriteGuard<()>>> {
+    let guard = lock(hash).try_write_owned().ok();
+    if guard.is_none() {
+        release(hash);
+    }
+
+    guard.map(|guard| BlobLock {
+        hash,
+        guard: Some(guard),
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct GcReport {
+    /// Whether orphaned blobs were only reported rather than deleted.
+    pub dry_run: bool,
+    pub scanned: usize,
+    /// Blobs deleted, or that would be deleted if this is a dry run.
+    pub swept: Vec<AvatarHash>,
+    pub reclaimed_bytes: u64,
+}
+
+/// Hashes of every blob referenced by a user's current avatar, in storage or in the database.
+async fn referenced(storage: &dyn Storage, database: &DatabaseService) -> anyhow::Result<FxHashSet<AvatarHash>> {
+    let mut hashes = storage
+        .references()
+        .await??
+        .into_iter()
+        .map(|(.., hash)| hash)
+        .collect::<FxHashSet<_>>();
+
+    hashes.extend(database.referenced_blobs().await?);
+    Ok(hashes)
+}
+
+pub async fn sweep(
+    storage: &dyn Storage,
+    database: &DatabaseService,
+    grace: Duration,
+    dry_run: bool,
+) -> anyhow::Result<GcReport> {
+    let now = unix_now();
+    let blobs = storage.blobs().await??;
+    let scanned = blobs.len();
+
+    let mut candidates = blobs
+        .into_iter()
+        .filter(|blob| now.saturating_sub(blob.modified) >= grace.as_secs())
+        .collect::<Vec<_>>();
+
+    if !candidates.is_empty() {
+        let referenced = referenced(storage, database).await?;
+        candidates.retain(|blob| !referenced.contains(&blob.hash));
+    }
+
+    let mut report = GcReport {
+        dry_run,
+        scanned,
+        swept: Vec::new(),
+        reclaimed_bytes: 0,
+    };
+
+    if dry_run {
+        report.reclaimed_bytes = candidates.iter().map(|blob| blob.size).sum();
+        report.swept = candidates.into_iter().map(|blob| blob.hash).collect();
+    } else if !candidates.is_empty() {
+        // Check again right before deleting, in case an upload referenced a candidate in the meantime.
+        let referenced = referenced(storage, database).await?;
+        for blob in candidates {
+            if referenced.contains(&blob.hash) {
+                continue
+            }
+
+            // Uploads of the blob since then either still hold it or have referenced it in the database by now.
+            let Some(_lock) = try_sweep(blob.hash) else { continue };
+
+            if !database.has_blob(blob.hash).await? && storage.delete(blob.hash).await?? {
+                report.swept.push(blob.hash);
+                report.reclaimed_bytes += blob.size;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        rt::{
+            spawn,
+            task::JoinHandle,
+        },
+        web,
+    };
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        random_uuid,
+        service::{
+            avatar::AvatarInfo,
+            database::AvatarMeta,
+        },
+        storage::BlobInfo,
+    };
+
+    const GRACE: Duration = Duration::from_secs(3600);
+
+    #[derive(Default)]
+    struct MemoryStorage {
+        blobs: Mutex<FxHashMap<AvatarHash, (BlobInfo, web::Bytes)>>,
+        refs: Mutex<FxHashMap<Uuid, AvatarHash>>,
+    }
+
+    impl MemoryStorage {
+        /// Stores a blob of a single byte, last stored `age` ago.
+        fn insert(&self, age: Duration) -> AvatarHash {
+            let hash = AvatarHash::of(random_uuid().as_bytes());
+            self.blobs.lock().insert(
+                hash,
+                (
+                    BlobInfo {
+                        hash,
+                        size: 1,
+                        modified: unix_now() - age.as_secs(),
+                    },
+                    web::Bytes::from_static(b"\0"),
+                ),
+            );
+            hash
+        }
+
+        fn contains(&self, hash: AvatarHash) -> bool {
+            self.blobs.lock().contains_key(&hash)
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        fn put(&self, hash: AvatarHash, data: web::Bytes) -> JoinHandle<anyhow::Result<()>> {
+            self.blobs.lock().insert(
+                hash,
+                (
+                    BlobInfo {
+                        hash,
+                        size: data.len() as u64,
+                        modified: unix_now(),
+                    },
+                    data,
+                ),
+            );
+            spawn(async { Ok(()) })
+        }
+
+        fn get(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<Option<web::Bytes>>> {
+            let data = self.blobs.lock().get(&hash).map(|(_, data)| data.clone());
+            spawn(async { Ok(data) })
+        }
+
+        fn reference(&self, user_id: Uuid) -> JoinHandle<anyhow::Result<Option<AvatarHash>>> {
+            let hash = self.refs.lock().get(&user_id).copied();
+            spawn(async move { Ok(hash) })
+        }
+
+        fn set_reference(&self, user_id: Uuid, hash: Option<AvatarHash>) -> JoinHandle<anyhow::Result<()>> {
+            match hash {
+                Some(hash) => self.refs.lock().insert(user_id, hash),
+                None => self.refs.lock().remove(&user_id),
+            };
+            spawn(async { Ok(()) })
+        }
+
+        fn blobs(&self) -> JoinHandle<anyhow::Result<Vec<BlobInfo>>> {
+            let blobs = self.blobs.lock().values().map(|&(info, _)| info).collect();
+            spawn(async { Ok(blobs) })
+        }
+
+        fn references(&self) -> JoinHandle<anyhow::Result<Vec<(Uuid, AvatarHash)>>> {
+            let refs = self.refs.lock().iter().map(|(&id, &hash)| (id, hash)).collect();
+            spawn(async { Ok(refs) })
+        }
+
+        fn delete(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<bool>> {
+            let deleted = self.blobs.lock().remove(&hash).is_some();
+            spawn(async move { Ok(deleted) })
+        }
+    }
+
+    #[actix_web::test]
+    async fn keeps_young_orphans() {
+        let (storage, database) = (MemoryStorage::default(), DatabaseService::open_in_memory().unwrap());
+        let young = storage.insert(GRACE / 2);
+        let old = storage.insert(GRACE * 2);
+
+        let report = sweep(&storage, &database, GRACE, false).await.unwrap();
+        assert_eq!(report.scanned, 2);
+        assert_eq!(report.swept, [old]);
+        assert_eq!(report.reclaimed_bytes, 1);
+        assert!(storage.contains(young));
+        assert!(!storage.contains(old));
+    }
+
+    #[actix_web::test]
+    async fn keeps_referenced_blobs() {
+        let (storage, database) = (MemoryStorage::default(), DatabaseService::open_in_memory().unwrap());
+        let [current, past, orphan] = [(); 3].map(|()| storage.insert(GRACE * 2));
+
+        storage.set_reference(random_uuid(), Some(current)).await.unwrap().unwrap();
+        let avatar = AvatarMeta {
+            owner: random_uuid(),
+            hash: past,
+            size: 1,
+            uploaded: 0,
+            info: AvatarInfo::default(),
+        };
+        database.push_version(avatar, 1).await.unwrap();
+
+        let report = sweep(&storage, &database, GRACE, false).await.unwrap();
+        assert_eq!(report.swept, [orphan]);
+        assert!(storage.contains(current));
+        assert!(storage.contains(past));
+    }
+
+    #[actix_web::test]
+    async fn dry_runs_delete_nothing() {
+        let (storage, database) = (MemoryStorage::default(), DatabaseService::open_in_memory().unwrap());
+        let orphan = storage.insert(GRACE * 2);
+
+        let report = sweep(&storage, &database, GRACE, true).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.swept, [orphan]);
+        assert_eq!(report.reclaimed_bytes, 1);
+        assert!(storage.contains(orphan));
+    }
+
+    #[actix_web::test]
+    async fn leaves_blobs_being_uploaded() {
+        let (storage, database) = (MemoryStorage::default(), DatabaseService::open_in_memory().unwrap());
+        let orphan = storage.insert(GRACE * 2);
+
+        let upload = hold(orphan).await;
+        assert!(sweep(&storage, &database, GRACE, false).await.unwrap().swept.is_empty());
+        assert!(storage.contains(orphan));
+
+        drop(upload);
+        assert!(!LOCKS.lock().contains_key(&orphan));
+        assert_eq!(sweep(&storage, &database, GRACE, false).await.unwrap().swept, [orphan]);
+    }
+}
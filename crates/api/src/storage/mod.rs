@@ -0,0 +1,139 @@
+//! Content-addressed avatar storage. Blobs are keyed by their SHA-256 hash, so identical uploads share the same blob,
+//! and each user holds a reference to the hash of their current avatar.
+
+pub mod fs;
+pub mod gc;
+
+use std::{
+    fmt::{
+        self,
+        Display,
+    },
+    str::FromStr,
+};
+
+use actix_web::{
+    rt::task::JoinHandle,
+    web,
+};
+use aws_lc_rs::digest::{
+    digest,
+    SHA256,
+};
+use serde::{
+    Serialize,
+    Serializer,
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct AvatarHash(pub [u8; 32]);
+
+impl AvatarHash {
+    #[inline]
+    pub fn of(data: &[u8]) -> Self {
+        Self(
+            digest(&SHA256, data)
+                .as_ref()
+                .try_into()
+                .expect("SHA-256 digests are 32 bytes"),
+        )
+    }
+}
+
+impl Display for AvatarHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("invalid avatar hash: expected 64 hexadecimal digits")]
+pub struct AvatarHashParseError;
+
+impl FromStr for AvatarHash {
+    type Err = AvatarHashParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // `from_str_radix` accepts a leading sign, so check the digits up front.
+        if s.len() != 64 || !s.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(AvatarHashParseError)
+        }
+
+        let mut hash = [0; 32];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| AvatarHashParseError)?;
+        }
+
+        Ok(Self(hash))
+    }
+}
+
+impl Serialize for AvatarHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BlobInfo {
+    pub hash: AvatarHash,
+    /// Size in bytes.
+    pub size: u64,
+    /// UNIX timestamp in seconds of when the blob was last stored.
+    pub modified: u64,
+}
+
+pub trait Storage: 'static + Send + Sync {
+    /// Stores the blob under its hash. If it's already stored, its modification time should be refreshed if possible,
+    /// so garbage collection doesn't sweep it before it's referenced.
+    fn put(&self, hash: AvatarHash, data: web::Bytes) -> JoinHandle<anyhow::Result<()>>;
+
+    fn get(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<Option<web::Bytes>>>;
+
+    /// Returns the hash of the user's current avatar, if any.
+    fn reference(&self, user_id: Uuid) -> JoinHandle<anyhow::Result<Option<AvatarHash>>>;
+
+    /// Points the user's current avatar to the hash, or removes it if `None`. Blobs no longer referenced are left in
+    /// place, as other users may still reference them.
+    fn set_reference(&self, user_id: Uuid, hash: Option<AvatarHash>) -> JoinHandle<anyhow::Result<()>>;
+
+    /// Lists every stored blob.
+    fn blobs(&self) -> JoinHandle<anyhow::Result<Vec<BlobInfo>>>;
+
+    /// Lists every user's current avatar.
+    fn references(&self) -> JoinHandle<anyhow::Result<Vec<(Uuid, AvatarHash)>>>;
+
+    /// Deletes the blob, returning `false` if it wasn't stored.
+    fn delete(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<bool>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hashes() {
+        let hash = AvatarHash::of(b"avatar");
+        assert_eq!(hash.to_string().parse::<AvatarHash>().unwrap(), hash);
+        assert_eq!(hash.to_string().to_uppercase().parse::<AvatarHash>().unwrap(), hash);
+
+        let hex = hash.to_string();
+        for bad in [
+            String::new(),
+            hex[..62].to_string(),
+            format!("{hex}00"),
+            format!("+{}", &hex[1..]),
+            format!("{}+{}", &hex[..2], &hex[3..]),
+            format!("{}g", &hex[..63]),
+            format!("{}é", &hex[..62]),
+        ] {
+            assert!(bad.parse::<AvatarHash>().is_err(), "{bad}");
+        }
+    }
+}
@@ -23,6 +23,8 @@ use figura_api::{
 };
 
 pub struct YggdrasilConfig {
+    /// Provider name users authenticated through this server are recorded with.
+    pub name: String,
     pub session_server: String,
     pub timeout: Duration,
 }
@@ -31,6 +33,7 @@ impl BackendConfig for YggdrasilConfig {
     #[inline]
     fn config(&self, locator: &mut dyn ServiceLocator) {
         locator.locate::<AuthService>().add(YggdrasilAuth {
+            name: self.name.clone(),
             session_server: self.session_server.clone(),
             timeout: self.timeout,
         });
@@ -38,15 +41,22 @@ impl BackendConfig for YggdrasilConfig {
 }
 
 pub struct YggdrasilAuth {
+    name: String,
     session_server: String,
     timeout: Duration,
 }
 
 impl Auth for YggdrasilAuth {
+    #[inline]
+    fn provider(&self) -> &str {
+        &self.name
+    }
+
     fn authenticate(&self, req: &HttpRequest, username: &str, server_id: Uuid) -> JoinHandle<anyhow::Result<Option<Uuid>>> {
         let &Self {
             ref session_server,
             timeout,
+            ..
         } = self;
 
         let http = req
@@ -0,0 +1,578 @@
+pub mod sigv4;
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use figura_api::{
+    actix_web::{
+        http::{
+            header::AUTHORIZATION,
+            Method,
+            StatusCode,
+        },
+        rt::{
+            spawn,
+            task::JoinHandle,
+        },
+        web,
+    },
+    anyhow,
+    awc,
+    native_roots,
+    service::{
+        avatar::AvatarService,
+        ServiceLocator,
+    },
+    storage::{
+        AvatarHash,
+        BlobInfo,
+        Storage,
+    },
+    unix_now,
+    uuid::Uuid,
+    BackendConfig,
+};
+use rustls::ClientConfig;
+
+use crate::sigv4::{
+    amz_date,
+    authorization,
+    parse_timestamp,
+    sha256,
+    uri_encode,
+    Credentials,
+    Request,
+};
+
+/// Largest object body read back from the service.
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+thread_local! {
+    static CLIENT: awc::Client = awc::Client::builder()
+        .connector(awc::Connector::new().rustls_0_23(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(native_roots())
+                .with_no_client_auth(),
+        )))
+        .timeout(Duration::from_secs(30))
+        .finish();
+}
+
+#[derive(Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to every object key, e.g. `figura/`.
+    pub prefix: String,
+    /// Base URL of the service, e.g. `https://s3.us-east-1.amazonaws.com`.
+    pub endpoint: String,
+    pub region: String,
+    /// Addresses the bucket in the path instead of the host name, which most self-hosted services need.
+    pub path_style: bool,
+    pub credentials: Credentials,
+}
+
+impl BackendConfig for S3Config {
+    #[inline]
+    fn config(&self, locator: &mut dyn ServiceLocator) {
+        locator
+            .locate::<AvatarService>()
+            .set_storage(Arc::new(S3Storage::new(self.clone())));
+    }
+}
+
+/// Stores blobs as `{prefix}blobs/{hash}` and references as `{prefix}refs/{user}` objects in a bucket.
+#[derive(Clone)]
+pub struct S3Storage {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    bucket: String,
+    scheme: String,
+    host: String,
+    /// Path every object key is appended to; the bucket if addressed path-style, otherwise empty.
+    root: String,
+    prefix: String,
+    region: String,
+    credentials: Credentials,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        let S3Config {
+            bucket,
+            prefix,
+            endpoint,
+            region,
+            path_style,
+            credentials,
+        } = config;
+
+        let endpoint = endpoint.trim_end_matches('/');
+        let (scheme, authority) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+        let (host, root) = if path_style {
+            (authority.to_string(), format!("/{}", uri_encode(&bucket, false)))
+        } else {
+            (format!("{bucket}.{authority}"), String::new())
+        };
+
+        Self {
+            inner: Arc::new(Inner {
+                bucket,
+                scheme: scheme.to_string(),
+                host,
+                root,
+                prefix,
+                region,
+                credentials,
+            }),
+        }
+    }
+}
+
+impl Inner {
+    /// Sends a signed request, signing the extra headers too.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        extra: &[(&str, &str)],
+        body: web::Bytes,
+    ) -> anyhow::Result<(StatusCode, web::Bytes)> {
+        let mut query = query
+            .iter()
+            .map(|&(name, value)| (uri_encode(name, false), uri_encode(value, false)))
+            .collect::<Vec<_>>();
+        query.sort_unstable();
+        let query = query
+            .into_iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let payload_hash = sha256(&body);
+        let date = amz_date(unix_now());
+
+        let mut headers = vec![
+            ("host", &*self.host),
+            ("x-amz-content-sha256", &*payload_hash),
+            ("x-amz-date", &*date),
+        ];
+        if let Some(ref token) = self.credentials.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+        headers.extend_from_slice(extra);
+
+        let authorization = authorization(&self.credentials, &self.region, &Request {
+            method: method.as_str(),
+            path,
+            query: &query,
+            headers: &headers,
+            payload_hash: &payload_hash,
+            amz_date: &date,
+        });
+
+        let mut url = format!("{}://{}{path}", self.scheme, self.host);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        // `awc` derives the `host` header from the URL itself.
+        let mut req = CLIENT.with(Clone::clone).request(method, url);
+        for &(name, value) in &headers[1..] {
+            req = req.insert_header((name, value));
+        }
+
+        let mut res = req
+            .insert_header((AUTHORIZATION, authorization))
+            .send_body(body)
+            .await
+            .map_err(|e| anyhow::anyhow!("couldn't send S3 request: {e}"))?;
+
+        let status = res.status();
+        Ok((status, res.body().limit(MAX_BODY).await?))
+    }
+
+    #[inline]
+    fn object_path(&self, key: &str) -> String {
+        format!("{}/{}", self.root, uri_encode(&format!("{}{key}", self.prefix), true))
+    }
+
+    /// Sends a request for the object, failing on unsuccessful responses other than `404` if the object may be missing.
+    async fn expect(
+        &self,
+        method: Method,
+        key: String,
+        body: web::Bytes,
+        missing: bool,
+    ) -> anyhow::Result<Option<web::Bytes>> {
+        let (status, body) = self.send(method, &self.object_path(&key), &[], &[], body).await?;
+        check(status, body, missing)
+    }
+
+    /// Lists every object under the key prefix, returning their keys without the prefixes, sizes, and modification
+    /// times.
+    async fn list(&self, key: &str) -> anyhow::Result<Vec<(String, u64, u64)>> {
+        let path = if self.root.is_empty() { "/" } else { &self.root };
+        let prefix = format!("{}{key}", self.prefix);
+
+        let mut objects = Vec::new();
+        let mut continuation = None::<String>;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", &*prefix)];
+            if let Some(ref token) = continuation {
+                query.push(("continuation-token", token));
+            }
+
+            let (status, body) = self.send(Method::GET, path, &query, &[], web::Bytes::new()).await?;
+            let body = check(status, body, false)?.unwrap_or_default();
+            let xml = std::str::from_utf8(&body)?;
+
+            for contents in xml.split("<Contents>").skip(1) {
+                let (Some(key), Some(size), Some(modified)) = (
+                    element(contents, "Key"),
+                    element(contents, "Size").and_then(|size| size.parse().ok()),
+                    element(contents, "LastModified").and_then(parse_timestamp),
+                ) else {
+                    anyhow::bail!("malformed S3 listing entry")
+                };
+
+                if let Some(key) = unescape(key).strip_prefix(&self.prefix) {
+                    objects.push((key.to_string(), size, modified));
+                }
+            }
+
+            match element(xml, "NextContinuationToken") {
+                Some(token) if element(xml, "IsTruncated") == Some("true") => continuation = Some(unescape(token)),
+                _ => break Ok(objects),
+            }
+        }
+    }
+}
+
+/// Returns the body of successful responses, or `None` if the object is missing and that's allowed.
+fn check(status: StatusCode, body: web::Bytes, missing: bool) -> anyhow::Result<Option<web::Bytes>> {
+    match status {
+        status if status.is_success() => Ok(Some(body)),
+        StatusCode::NOT_FOUND if missing => Ok(None),
+        status => anyhow::bail!("S3 request failed with {status}: {}", String::from_utf8_lossy(&body)),
+    }
+}
+
+/// Returns the text of the first element with the name, which mustn't have attributes.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{name}>"))?;
+    Some(&xml[start..start + len])
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+impl Storage for S3Storage {
+    fn put(&self, hash: AvatarHash, data: web::Bytes) -> JoinHandle<anyhow::Result<()>> {
+        let inner = self.inner.clone();
+        spawn(async move {
+            let key = format!("blobs/{hash}");
+            if inner
+                .expect(Method::HEAD, key.clone(), web::Bytes::new(), true)
+                .await?
+                .is_none()
+            {
+                inner.expect(Method::PUT, key, data, false).await?;
+            } else {
+                // Copying the blob onto itself refreshes its modification time without uploading it again.
+                let source = uri_encode(&format!("{}/{}{key}", inner.bucket, inner.prefix), true);
+                let (status, body) = inner
+                    .send(
+                        Method::PUT,
+                        &inner.object_path(&key),
+                        &[],
+                        &[("x-amz-copy-source", &source), ("x-amz-metadata-directive", "REPLACE")],
+                        web::Bytes::new(),
+                    )
+                    .await?;
+                check(status, body, false)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<Option<web::Bytes>>> {
+        let inner = self.inner.clone();
+        spawn(async move {
+            inner
+                .expect(Method::GET, format!("blobs/{hash}"), web::Bytes::new(), true)
+                .await
+        })
+    }
+
+    fn reference(&self, user_id: Uuid) -> JoinHandle<anyhow::Result<Option<AvatarHash>>> {
+        let inner = self.inner.clone();
+        spawn(async move {
+            let Some(body) = inner
+                .expect(Method::GET, format!("refs/{user_id}"), web::Bytes::new(), true)
+                .await?
+            else {
+                return Ok(None)
+            };
+
+            Ok(Some(std::str::from_utf8(&body)?.trim().parse()?))
+        })
+    }
+
+    fn set_reference(&self, user_id: Uuid, hash: Option<AvatarHash>) -> JoinHandle<anyhow::Result<()>> {
+        let inner = self.inner.clone();
+        spawn(async move {
+            let key = format!("refs/{user_id}");
+            match hash {
+                Some(hash) => inner.expect(Method::PUT, key, hash.to_string().into(), false).await?,
+                None => inner.expect(Method::DELETE, key, web::Bytes::new(), true).await?,
+            };
+
+            Ok(())
+        })
+    }
+
+    fn blobs(&self) -> JoinHandle<anyhow::Result<Vec<BlobInfo>>> {
+        let inner = self.inner.clone();
+        spawn(async move {
+            Ok(inner
+                .list("blobs/")
+                .await?
+                .into_iter()
+                .filter_map(|(key, size, modified)| {
+                    Some(BlobInfo {
+                        hash: key.strip_prefix("blobs/")?.parse().ok()?,
+                        size,
+                        modified,
+                    })
+                })
+                .collect())
+        })
+    }
+
+    fn references(&self) -> JoinHandle<anyhow::Result<Vec<(Uuid, AvatarHash)>>> {
+        let storage = self.clone();
+        spawn(async move {
+            let mut refs = Vec::new();
+            for (key, ..) in storage.inner.list("refs/").await? {
+                let Some(user_id) = key.strip_prefix("refs/").and_then(|id| id.parse().ok()) else {
+                    continue
+                };
+
+                // The reference may have been removed since listing it.
+                if let Some(hash) = storage.reference(user_id).await?? {
+                    refs.push((user_id, hash));
+                }
+            }
+
+            Ok(refs)
+        })
+    }
+
+    fn delete(&self, hash: AvatarHash) -> JoinHandle<anyhow::Result<bool>> {
+        let inner = self.inner.clone();
+        spawn(async move {
+            let key = format!("blobs/{hash}");
+            if inner
+                .expect(Method::HEAD, key.clone(), web::Bytes::new(), true)
+                .await?
+                .is_none()
+            {
+                return Ok(false)
+            }
+
+            inner.expect(Method::DELETE, key, web::Bytes::new(), false).await?;
+            Ok(true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Mutex,
+    };
+
+    use figura_api::{
+        actix::System,
+        actix_web::{
+            App,
+            HttpRequest,
+            HttpResponse,
+            HttpServer,
+        },
+    };
+
+    use super::*;
+
+    type Objects = Mutex<HashMap<String, web::Bytes>>;
+
+    fn credentials() -> Credentials {
+        Credentials {
+            access_key: "test".to_string(),
+            secret_key: "secret".to_string(),
+            session_token: None,
+        }
+    }
+
+    /// Bare-bones S3 that verifies signatures and keeps objects in memory.
+    async fn fake(req: HttpRequest, body: web::Bytes, objects: web::Data<Objects>) -> HttpResponse {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+        };
+
+        let signed = header("authorization")
+            .split_once("SignedHeaders=")
+            .and_then(|(.., rest)| rest.split(',').next())
+            .unwrap_or_default()
+            .split(';')
+            .map(|name| (name, header(name)))
+            .collect::<Vec<_>>();
+
+        let payload_hash = header("x-amz-content-sha256");
+        let expected = authorization(&credentials(), "us-east-1", &Request {
+            method: req.method().as_str(),
+            path: req.path(),
+            query: req.query_string(),
+            headers: &signed,
+            payload_hash,
+            amz_date: header("x-amz-date"),
+        });
+
+        if payload_hash != sha256(&body) || header("authorization") != expected {
+            return HttpResponse::Forbidden().finish()
+        }
+
+        let mut objects = objects.lock().unwrap();
+        match *req.method() {
+            Method::GET if req.path() == "/avatars" => {
+                let prefix = req
+                    .query_string()
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("prefix="))
+                    .unwrap_or_default()
+                    .replace("%2F", "/");
+
+                let date = amz_date(unix_now());
+                let modified = format!(
+                    "{}-{}-{}T{}:{}:{}.000Z",
+                    &date[0..4],
+                    &date[4..6],
+                    &date[6..8],
+                    &date[9..11],
+                    &date[11..13],
+                    &date[13..15]
+                );
+
+                let mut xml = "<ListBucketResult><IsTruncated>false</IsTruncated>".to_string();
+                for (path, data) in objects.iter() {
+                    let key = path.trim_start_matches("/avatars/");
+                    if key.starts_with(&prefix) {
+                        xml += &format!(
+                            "<Contents><Key>{key}</Key><LastModified>{modified}</LastModified><Size>{}</Size></Contents>",
+                            data.len()
+                        );
+                    }
+                }
+
+                HttpResponse::Ok().body(xml + "</ListBucketResult>")
+            }
+            Method::GET | Method::HEAD => match objects.get(req.path()) {
+                Some(data) => HttpResponse::Ok().body(data.clone()),
+                None => HttpResponse::NotFound().finish(),
+            },
+            Method::PUT if !header("x-amz-copy-source").is_empty() => {
+                match objects.get(&format!("/{}", header("x-amz-copy-source"))).cloned() {
+                    Some(data) => {
+                        objects.insert(req.path().to_string(), data);
+                        HttpResponse::Ok().finish()
+                    }
+                    None => HttpResponse::NotFound().finish(),
+                }
+            }
+            Method::PUT => {
+                objects.insert(req.path().to_string(), body);
+                HttpResponse::Ok().finish()
+            }
+            Method::DELETE => {
+                objects.remove(req.path());
+                HttpResponse::NoContent().finish()
+            }
+            _ => HttpResponse::MethodNotAllowed().finish(),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_fake() -> anyhow::Result<()> {
+        System::new().block_on(async {
+            let objects = web::Data::new(Objects::default());
+            let server = HttpServer::new({
+                let objects = objects.clone();
+                move || App::new().app_data(objects.clone()).default_service(web::to(fake))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))?;
+
+            let addr = server.addrs()[0];
+            let server = server.run();
+            let handle = server.handle();
+            spawn(server);
+
+            let storage = S3Storage::new(S3Config {
+                bucket: "avatars".to_string(),
+                prefix: "figura/".to_string(),
+                endpoint: format!("http://{addr}"),
+                region: "us-east-1".to_string(),
+                path_style: true,
+                credentials: credentials(),
+            });
+
+            let user = Uuid::from_u128(1);
+            let data = web::Bytes::from_static(b"avatar");
+            let hash = AvatarHash::of(&data);
+
+            assert_eq!(storage.get(hash).await??, None);
+            assert_eq!(storage.reference(user).await??, None);
+
+            storage.put(hash, data.clone()).await??;
+            storage.put(hash, data.clone()).await??;
+            storage.set_reference(user, Some(hash)).await??;
+
+            assert_eq!(storage.get(hash).await??, Some(data.clone()));
+            assert_eq!(storage.reference(user).await??, Some(hash));
+            assert!(objects.lock().unwrap().contains_key(&format!("/avatars/figura/blobs/{hash}")));
+
+            let blobs = storage.blobs().await??;
+            assert_eq!(blobs.len(), 1);
+            assert_eq!((blobs[0].hash, blobs[0].size), (hash, data.len() as u64));
+            assert_eq!(storage.references().await??, [(user, hash)]);
+
+            storage.set_reference(user, None).await??;
+            assert_eq!(storage.reference(user).await??, None);
+            assert!(storage.references().await??.is_empty());
+
+            assert!(storage.delete(hash).await??);
+            assert!(!storage.delete(hash).await??);
+            assert_eq!(storage.get(hash).await??, None);
+
+            handle.stop(true).await;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,10 @@
+#![no_main]
+
+use std::str::FromStr;
+
+use figura_api::endpoint::header::AccessToken;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = AccessToken::from_str(data);
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use figura_api::{
+    actix_web::web,
+    socket::message::{
+        Codec,
+        C2S,
+    },
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = C2S::try_from(web::Bytes::copy_from_slice(data)) {
+        // Whatever decodes must survive a round trip.
+        assert_eq!(C2S::decode(msg.to_bytes()).ok(), Some(msg));
+    }
+});
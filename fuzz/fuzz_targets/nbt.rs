@@ -0,0 +1,21 @@
+#![no_main]
+
+use figura_api::{
+    nbt::{
+        self,
+        Limits,
+    },
+    service::avatar::AvatarInfo,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Uploads are compressed, but reading trees directly too lets mutations reach the parser without having to keep
+    // the compressed stream intact.
+    let _ = nbt::read(data, &Limits {
+        max_depth: 128,
+        max_elements: 1 << 18,
+        max_size: 1 << 20,
+    });
+    let _ = AvatarInfo::parse(data, 1 << 15);
+});
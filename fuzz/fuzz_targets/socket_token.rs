@@ -0,0 +1,15 @@
+#![no_main]
+
+use figura_api::{
+    actix_web::web,
+    socket::{
+        message::C2S,
+        state::State,
+    },
+};
+use libfuzzer_sys::fuzz_target;
+
+// Exercises the UTF-8 and UUID parsing `Socket` performs on `C2S::Token` frames.
+fuzz_target!(|data: &[u8]| {
+    let _ = State::AwaitingToken.next(C2S::Token(web::Bytes::copy_from_slice(data)));
+});
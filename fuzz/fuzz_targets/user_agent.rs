@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::str::FromStr;
+
+use figura_api::endpoint::header::UserAgent;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok(agent) = UserAgent::from_str(data) {
+        assert!(!agent.name.is_empty() && !agent.version.is_empty());
+    }
+});
@@ -1,7 +1,15 @@
 use std::{
-    fs::File,
+    env,
+    fs::{
+        self,
+        File,
+    },
     io::BufReader,
-    num::ParseIntError,
+    num::{
+        NonZeroU64,
+        NonZeroUsize,
+        ParseIntError,
+    },
     path::PathBuf,
     time::Duration,
 };
@@ -10,8 +18,19 @@ use clap::Parser;
 use figura_api::{
     actix::System,
     anyhow,
+    cluster::{
+        peer::PeerConfig,
+        redis::RedisConfig,
+        ClusterConfig,
+    },
     log::LevelFilter,
+    service::{
+        avatar::AvatarConfig,
+        socket::SocketConfig,
+    },
+    uuid::Uuid,
     Backend,
+    BackendConfig,
 };
 
 #[derive(Parser, Debug)]
@@ -32,6 +51,118 @@ struct Args {
     /// Access token validation timeout.
     #[arg(short, long, value_parser = duration_str, default_value = "600")]
     access_timeout: Duration,
+    /// Maximum access tokens a single user may hold at once; the least recently refreshed ones are revoked first.
+    #[arg(long, default_value = "8")]
+    max_sessions: NonZeroUsize,
+    /// Maximum sockets a single user may have open at once; the oldest ones are closed first.
+    #[arg(long, default_value = "4")]
+    max_connections: NonZeroUsize,
+    /// How often sockets are pinged, in seconds.
+    #[arg(long, value_parser = nonzero_duration_str, default_value = "10")]
+    heartbeat_interval: Duration,
+    /// How long a socket may stay silent before it's closed, in seconds.
+    #[arg(long, value_parser = nonzero_duration_str, default_value = "30")]
+    idle_timeout: Duration,
+    /// How long a socket may stay connected without authenticating, in seconds.
+    #[arg(long, value_parser = nonzero_duration_str, default_value = "10")]
+    auth_timeout: Duration,
+    /// Largest message in bytes accepted from socket clients.
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_message_size: usize,
+    /// Accepts text frames carrying a JSON representation of the socket protocol, for debugging.
+    #[arg(long)]
+    json_debug: bool,
+    /// Bytes queued for a socket past which pings to it are dropped instead of delivered.
+    #[arg(long, default_value_t = 256 * 1024)]
+    outbound_high_water: usize,
+    /// Bytes queued for a socket past which it's disconnected.
+    #[arg(long, default_value_t = 1024 * 1024)]
+    outbound_limit: usize,
+    /// Maximum users a single socket may subscribe to.
+    #[arg(long, default_value_t = 512)]
+    max_subscriptions: usize,
+    /// Negotiates `permessage-deflate` with socket clients that offer it.
+    #[arg(long)]
+    compression: bool,
+    /// Size in bytes below which outgoing socket messages aren't compressed.
+    #[arg(long, default_value_t = 64)]
+    compression_threshold: usize,
+
+    /// User UUIDs permitted to access the administration endpoints.
+    #[arg(long = "admin")]
+    admins: Vec<Uuid>,
+    /// The JSON file bans used to be persisted to, imported into the database once if it exists.
+    #[arg(long, default_value = "bans.json")]
+    bans: PathBuf,
+    /// Enables private server mode, only letting users listed in this file authenticate. It's reloaded automatically
+    /// when modified.
+    #[arg(long)]
+    allow_list: Option<PathBuf>,
+    /// How often the allow-list is checked for modifications, in seconds.
+    #[arg(long, value_parser = nonzero_duration_str, default_value = "5")]
+    allow_list_interval: Duration,
+
+    /// SQLite database file metadata is persisted to.
+    #[arg(long, default_value = "figura.db")]
+    database: PathBuf,
+
+    /// Directory avatars are stored in.
+    #[arg(long, default_value = "avatars")]
+    avatars: PathBuf,
+    /// Largest avatar in bytes users may upload.
+    #[arg(long, default_value_t = 100 * 1024)]
+    max_avatar_size: u64,
+    /// How many distinct avatars, current or kept to roll back to, a single user may store. Uploads past that are
+    /// refused until the user forgets some of their history.
+    #[arg(long, default_value_t = 5)]
+    max_avatars: u64,
+    /// Total bytes all stored avatars may take up; unlimited if unset.
+    #[arg(long)]
+    storage_budget: Option<u64>,
+    /// How many of each user's latest uploads are kept to roll back to; 0 keeps none.
+    #[arg(long, default_value_t = 5)]
+    avatar_history: usize,
+    /// How often orphaned avatar blobs are swept, in seconds; 0 only sweeps when requested through the admin API.
+    #[arg(long, value_parser = duration_str, default_value = "3600")]
+    gc_interval: Duration,
+    /// How long orphaned avatar blobs are kept before they may be swept, in seconds.
+    #[arg(long, value_parser = duration_str, default_value = "3600")]
+    gc_grace: Duration,
+    /// Stores avatars in this S3 bucket instead of the avatar directory. Credentials are read from the
+    /// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`, and optionally `AWS_SESSION_TOKEN` environment variables.
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    s3_bucket: Option<String>,
+    /// Prepended to every object key in the S3 bucket.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "")]
+    s3_prefix: String,
+    /// Base URL of the S3-compatible service; defaults to AWS in the configured region.
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    s3_endpoint: Option<String>,
+    /// Region the S3 bucket is in.
+    #[cfg(feature = "s3")]
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+    /// Addresses the S3 bucket in the path instead of the host name, as e.g. MinIO needs.
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    s3_path_style: bool,
+
+    /// Base URL of another instance to share access tokens and socket traffic with, enabling cluster mode.
+    #[arg(long = "peer", requires = "cluster_secret")]
+    peers: Vec<String>,
+    /// File containing the secret shared by every instance of the cluster.
+    #[arg(long)]
+    cluster_secret: Option<PathBuf>,
+    /// Additional certificate authorities in PEM format to trust when connecting to peers.
+    #[arg(long)]
+    peer_ca: Option<PathBuf>,
+    /// URL of a RESP server (Redis, Valkey, KeyDB...) to share access tokens and socket traffic through, enabling
+    /// cluster mode.
+    #[arg(long, conflicts_with = "peers")]
+    redis: Option<String>,
 
     /// Mojang's official Minecraft session server.
     #[cfg(feature = "mojang")]
@@ -57,10 +188,20 @@ fn duration_str(arg: &str) -> Result<Duration, ParseIntError> {
     Ok(Duration::from_secs(arg.parse()?))
 }
 
+#[inline]
+fn nonzero_duration_str(arg: &str) -> Result<Duration, ParseIntError> {
+    Ok(Duration::from_secs(arg.parse::<NonZeroU64>()?.get()))
+}
+
 fn main() -> anyhow::Result<()> {
     System::new().block_on(async move {
         #[cfg(any(feature = "mojang", feature = "ely"))]
         use figura_auth_yggdrasil::YggdrasilConfig;
+        #[cfg(feature = "s3")]
+        use figura_storage_s3::{
+            sigv4::Credentials,
+            S3Config,
+        };
 
         env_logger::builder()
             .filter_level(LevelFilter::Info)
@@ -72,6 +213,41 @@ fn main() -> anyhow::Result<()> {
             Err(e) => return Ok(e.print()?),
         };
 
+        #[allow(unused_mut)]
+        let mut configs: Vec<Box<dyn BackendConfig>> = vec![
+            // The authentication stack prioritizes Mojang's Yggdrasil server first.
+            #[cfg(feature = "mojang")]
+            Box::new(YggdrasilConfig {
+                name: "mojang".to_string(),
+                session_server: args.mojang_session_server,
+                timeout: args.mojang_session_timeout,
+            }),
+            #[cfg(feature = "ely")]
+            Box::new(YggdrasilConfig {
+                name: "ely".to_string(),
+                session_server: args.ely_session_server,
+                timeout: args.ely_session_timeout,
+            }),
+        ];
+
+        #[cfg(feature = "s3")]
+        if let Some(bucket) = args.s3_bucket {
+            configs.push(Box::new(S3Config {
+                bucket,
+                prefix: args.s3_prefix,
+                endpoint: args
+                    .s3_endpoint
+                    .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", args.s3_region)),
+                region: args.s3_region,
+                path_style: args.s3_path_style,
+                credentials: Credentials {
+                    access_key: env::var("AWS_ACCESS_KEY_ID")?,
+                    secret_key: env::var("AWS_SECRET_ACCESS_KEY")?,
+                    session_token: env::var("AWS_SESSION_TOKEN").ok(),
+                },
+            }));
+        }
+
         Backend {
             port: args.port,
             key: BufReader::new(File::open(args.key)?),
@@ -79,20 +255,48 @@ fn main() -> anyhow::Result<()> {
 
             server_id_timeout: args.server_id_timeout,
             access_timeout: args.access_timeout,
+            max_sessions: args.max_sessions,
+            socket: SocketConfig {
+                max_connections: args.max_connections,
+                heartbeat_interval: args.heartbeat_interval,
+                idle_timeout: args.idle_timeout,
+                auth_timeout: args.auth_timeout,
+                max_message_size: args.max_message_size,
+                json_debug: args.json_debug,
+                outbound_high_water: args.outbound_high_water,
+                outbound_limit: args.outbound_limit,
+                max_subscriptions: args.max_subscriptions,
+                compression: args.compression,
+                compression_threshold: args.compression_threshold,
+            },
+
+            admins: args.admins,
+            bans: args.bans,
+            allow_list: args.allow_list,
+            allow_list_interval: args.allow_list_interval,
+            cluster: match (args.redis, args.cluster_secret) {
+                (Some(url), ..) => Some(ClusterConfig::Redis(RedisConfig { url })),
+                (None, Some(secret)) if !args.peers.is_empty() => Some(ClusterConfig::Peers(PeerConfig {
+                    peers: args.peers,
+                    secret: fs::read_to_string(secret)?.trim().as_bytes().to_vec(),
+                    peer_ca: args.peer_ca,
+                })),
+                _ => None,
+            },
+
+            database: args.database,
+
+            avatars: args.avatars,
+            avatar: AvatarConfig {
+                max_size: args.max_avatar_size,
+                max_avatars: args.max_avatars,
+                storage_budget: args.storage_budget,
+                gc_interval: (!args.gc_interval.is_zero()).then_some(args.gc_interval),
+                gc_grace: args.gc_grace,
+                history: args.avatar_history,
+            },
 
-            configs: vec![
-                // The authentication stack prioritizes Mojang's Yggdrasil server first.
-                #[cfg(feature = "mojang")]
-                Box::new(YggdrasilConfig {
-                    session_server: args.mojang_session_server,
-                    timeout: args.mojang_session_timeout,
-                }),
-                #[cfg(feature = "ely")]
-                Box::new(YggdrasilConfig {
-                    session_server: args.ely_session_server,
-                    timeout: args.ely_session_timeout,
-                }),
-            ],
+            configs,
         }
         .run()
         .await